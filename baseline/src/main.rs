@@ -1,10 +1,7 @@
-#![feature(test)]
-mod omq;
-mod request;
+#![cfg_attr(feature = "nightly", feature(test))]
 
 use clap::Parser;
-use omq::ObliviousMultiQueue;
-use request::Request;
+use sparta::{MessageStore, ObliviousMultiQueue, Record, Result, DEFAULT_MESSAGE_SIZE};
 use std::time::UNIX_EPOCH;
 
 /// Baseline oblivious sort based multiqueue.
@@ -15,7 +12,7 @@ struct Args {
     sends: usize,
 
     /// Number of messages to fetch from the database.
-    fetches: usize,
+    fetches: u64,
 
     /// Total number of threads available.
     threads: usize,
@@ -30,32 +27,58 @@ struct Args {
 }
 
 fn main() {
-    let args = Args::parse();
+    if let Err(e) = run(Args::parse()) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut o = ObliviousMultiQueue::new(args.threads);
+fn run(args: Args) -> Result<()> {
+    let mut o: ObliviousMultiQueue<DEFAULT_MESSAGE_SIZE> = ObliviousMultiQueue::new(args.threads)?;
 
-    let sends: Vec<Request> = (0..args.sends)
-        .map(|x| Request::new_send(0, x.try_into().unwrap()))
+    let sends: Vec<Record<DEFAULT_MESSAGE_SIZE>> = (0..args.sends)
+        .map(|x| Record::send(0, sparta::message(x)))
         .collect();
-    o.batch_send(sends);
-    let results: Vec<u128> = (0..(args.runs + args.warmup_runs))
+    o.batch_send(sends)?;
+    let results = (0..(args.runs + args.warmup_runs))
         .map(|_| {
             let start = std::time::SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_nanos();
-            let _ = o.batch_fetch(vec![Request::new_fetch(0, args.fetches)]);
+            let _ = o.batch_fetch(vec![Record::fetch(0, args.fetches)])?;
             let end = std::time::SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_nanos();
-            end - start
+            Ok(end - start)
         })
-        .collect();
+        .collect::<Result<Vec<u128>>>()?;
 
     print!("{}\t", args.sends);
     for result in results[args.warmup_runs..].iter() {
         print!("{}\t", *result as f64 / 1000000000.0);
     }
     println!();
+    Ok(())
+}
+
+#[cfg(all(test, feature = "nightly"))]
+mod tests {
+    use super::*;
+
+    extern crate test;
+    use test::Bencher;
+
+    #[bench]
+    fn bench_fetch(b: &mut Bencher) {
+        let mut o: ObliviousMultiQueue<DEFAULT_MESSAGE_SIZE> = ObliviousMultiQueue::new(8).unwrap();
+
+        let sends: Vec<Record<DEFAULT_MESSAGE_SIZE>> = (0..1048576)
+            .map(|x| Record::send(0, sparta::message(x)))
+            .collect();
+        o.batch_send(sends).unwrap();
+
+        b.iter(|| o.batch_fetch(vec![Record::fetch(0, 1048575)]).unwrap());
+    }
 }
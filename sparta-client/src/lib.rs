@@ -0,0 +1,485 @@
+//! Client side of sparta: holds a user's identity and key, builds send and
+//! fetch requests for `sparta serve`, and opens the deliveries it returns.
+
+use sparta::{Envelope, Record, Request, Response, Uid, UserKeys, KEY_SIZE};
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Fetches a recipient waits for a missing message before it gives up on it
+/// and hands out the messages held behind it.
+pub const DEFAULT_GAP_TIMEOUT: u64 = 8;
+
+// sequence numbers carry the sender's session above the message's number in it
+const SESSION_SHIFT: u32 = 20;
+const COUNTER_MASK: u64 = (1 << SESSION_SHIFT) - 1;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+/// A message as handed to the recipient, with its place in the sender's
+/// conversation with the recipient: the sender's session in the high bits of
+/// `seq`, the message's number within it in the low 20. Group sends are not
+/// numbered and have `seq` 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message<const N: usize> {
+    pub sender: Uid,
+    pub seq: u64,
+    pub body: [u8; N],
+}
+
+/// Messages received from one sender in its current session, held back until
+/// every message sent before them has arrived.
+struct Conversation<const N: usize> {
+    session: u64,
+    next: u64,
+    held: BTreeMap<u64, [u8; N]>,
+    // fetch at which the first message now held back arrived
+    waiting_since: u64,
+}
+
+impl<const N: usize> Conversation<N> {
+    fn new(session: u64) -> Self {
+        Conversation {
+            session,
+            next: (session << SESSION_SHIFT) | 1,
+            held: BTreeMap::new(),
+            waiting_since: 0,
+        }
+    }
+
+    /// Hands out the held messages that follow the last one handed out.
+    fn release(&mut self, sender: Uid, messages: &mut Vec<Message<N>>) {
+        while let Some(body) = self.held.remove(&self.next) {
+            messages.push(Message {
+                sender,
+                seq: self.next,
+                body,
+            });
+            self.next += 1;
+        }
+    }
+
+    /// Hands out every held message in order, giving up on all the missing
+    /// messages between them.
+    fn flush(&mut self, sender: Uid, messages: &mut Vec<Message<N>>) {
+        let held = std::mem::take(&mut self.held);
+        messages.extend(
+            held.into_iter()
+                .map(|(seq, body)| Message { sender, seq, body }),
+        );
+    }
+
+    /// Gives up on the missing messages ahead of the first held one.
+    fn skip(&mut self, sender: Uid, messages: &mut Vec<Message<N>>) {
+        if let Some(&first) = self.held.keys().next() {
+            self.next = first;
+            self.release(sender, messages);
+        }
+    }
+}
+
+/// A single user. Messages are `N` bytes wide and must match the message size
+/// the server was started with.
+pub struct Client<const N: usize> {
+    uid: Uid,
+    key: [u8; KEY_SIZE],
+    sent: u64,
+    expected: u64,
+    received: u64,
+    // stamp of the last fetch, which the server requires to grow
+    stamp: u64,
+    /// Fetches to wait for a missing message before handing out the messages
+    /// of the same sender held back behind it.
+    pub gap_timeout: u64,
+
+    // session this client numbers its sends in, from the clock at its start
+    session: u64,
+    // last sequence number sent to each recipient
+    seqs: HashMap<Uid, u64>,
+    // number of fetches received
+    fetches: u64,
+    conversations: HashMap<Uid, Conversation<N>>,
+}
+
+impl<const N: usize> Client<N> {
+    pub fn new(uid: Uid, key: [u8; KEY_SIZE]) -> Self {
+        Client {
+            uid,
+            key,
+            sent: 0,
+            expected: 0,
+            received: 0,
+            stamp: 0,
+            gap_timeout: DEFAULT_GAP_TIMEOUT,
+            session: now_millis(),
+            seqs: HashMap::new(),
+            fetches: 0,
+            conversations: HashMap::new(),
+        }
+    }
+
+    /// Client for `uid` with its key derived from the deployment's master key.
+    pub fn from_keys(keys: &UserKeys, uid: Uid) -> Self {
+        Client::new(uid, keys.key(uid))
+    }
+
+    pub fn uid(&self) -> Uid {
+        self.uid
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Records that `count` more messages are on their way to this user, e.g.
+    /// as agreed with the sender out of band.
+    pub fn expect(&mut self, count: u64) {
+        self.expected += count;
+    }
+
+    /// Number of expected messages that have not been received yet.
+    pub fn pending(&self) -> u64 {
+        self.expected.saturating_sub(self.received)
+    }
+
+    /// Send numbered after the previous send to `recipient`. Numbers restart
+    /// in a new session, taken from the clock, whenever the client starts, so
+    /// a restarted client never reuses them. A request the server refuses can
+    /// be submitted again as is; one that is given up on leaves a gap the
+    /// recipient waits out for `gap_timeout` fetches.
+    pub fn send(&mut self, recipient: Uid, message: &[u8; N]) -> Request {
+        self.sent += 1;
+        let seq = self
+            .seqs
+            .entry(recipient)
+            .or_insert(self.session << SESSION_SHIFT);
+        *seq = match *seq & COUNTER_MASK {
+            // out of numbers, so the conversation moves on to a new session
+            COUNTER_MASK => (((*seq >> SESSION_SHIFT) + 1) << SESSION_SHIFT) | 1,
+            _ => *seq + 1,
+        };
+        Request::Send(Envelope::seal_send(
+            &self.key, self.uid, recipient, *seq, message,
+        ))
+    }
+
+    /// Send delivered to every member of group `gid`. Members cannot tell a
+    /// group's messages from others of the same sender, so these are not
+    /// numbered.
+    pub fn send_to_group(&mut self, gid: Uid, message: &[u8; N]) -> Request {
+        self.sent += 1;
+        Request::GroupSend(Envelope::seal_send(&self.key, self.uid, gid, 0, message))
+    }
+
+    /// Stamp larger than any this user sent before. Stamps follow the clock,
+    /// so they keep growing across restarts of the client.
+    fn next_stamp(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        self.stamp = cmp::max(self.stamp + 1, now);
+        self.stamp
+    }
+
+    /// Fetch of a fixed `volume`. The server always answers with exactly
+    /// `volume` deliveries, so a constant volume hides how many are real.
+    pub fn fetch(&mut self, volume: u64) -> Request {
+        let stamp = self.next_stamp();
+        Request::Fetch(Envelope::seal_fetch(&self.key, self.uid, stamp, volume))
+    }
+
+    /// Fetch sized to the number of pending messages.
+    pub fn fetch_pending(&mut self) -> Request {
+        self.fetch(self.pending())
+    }
+
+    /// Acknowledges the deliveries of the last fetch, so that a server holding
+    /// deliveries for acknowledgement does not deliver them again. Send it
+    /// only once those deliveries have been opened; deliveries of earlier
+    /// fetches that never arrived are still delivered again.
+    pub fn ack(&self) -> Request {
+        Request::Ack(Envelope::seal_ack(&self.key, self.uid, self.stamp))
+    }
+
+    /// Opens the deliveries of a fetch and returns the real messages, dropping
+    /// the dummy records that pad the response. Messages from each sender come
+    /// out in the order they were sent: one that arrives ahead of an earlier
+    /// message is held until the gap is filled or has stayed open for
+    /// `gap_timeout` fetches, and one delivered again is dropped. Once a
+    /// sender starts a new session, every message held from its old one is
+    /// handed out in order and late ones from it are dropped.
+    pub fn open(&mut self, envelopes: &[Envelope]) -> io::Result<Vec<Message<N>>> {
+        let records = envelopes
+            .iter()
+            .map(|envelope| {
+                envelope
+                    .open_delivery(&self.key)
+                    .ok_or_else(|| invalid("delivery failed to open"))
+            })
+            .collect::<io::Result<Vec<Record<N>>>>()?;
+        Ok(self.receive(records))
+    }
+
+    /// Same as `open` for records taken directly from `batch_fetch` by an
+    /// embedded load balancer.
+    pub fn receive(&mut self, records: Vec<Record<N>>) -> Vec<Message<N>> {
+        self.fetches += 1;
+        let mut messages = Vec::new();
+        for record in records {
+            if record.uid != self.uid || !record.is_send() {
+                continue;
+            }
+            if record.seq == 0 {
+                self.received += 1;
+                messages.push(Message {
+                    sender: record.sender,
+                    seq: 0,
+                    body: record.message,
+                });
+                continue;
+            }
+
+            let session = record.seq >> SESSION_SHIFT;
+            let conversation = self
+                .conversations
+                .entry(record.sender)
+                .or_insert_with(|| Conversation::new(session));
+            if session > conversation.session {
+                // the sender restarted, so missing messages of its old session
+                // are not coming
+                conversation.flush(record.sender, &mut messages);
+                *conversation = Conversation::new(session);
+            }
+            if record.seq < conversation.next || conversation.held.contains_key(&record.seq) {
+                continue;
+            }
+            self.received += 1;
+            if conversation.held.is_empty() {
+                conversation.waiting_since = self.fetches;
+            }
+            conversation.held.insert(record.seq, record.message);
+            let next = conversation.next;
+            conversation.release(record.sender, &mut messages);
+            if conversation.next != next {
+                conversation.waiting_since = self.fetches;
+            }
+        }
+
+        let mut timed_out: Vec<Uid> = self
+            .conversations
+            .iter()
+            .filter(|(_, conversation)| {
+                !conversation.held.is_empty()
+                    && self.fetches - conversation.waiting_since >= self.gap_timeout
+            })
+            .map(|(&sender, _)| sender)
+            .collect();
+        timed_out.sort();
+        for sender in timed_out {
+            let conversation = self.conversations.get_mut(&sender).unwrap();
+            conversation.skip(sender, &mut messages);
+            conversation.waiting_since = self.fetches;
+        }
+        messages
+    }
+
+    /// Messages known to be missing, as the sequence numbers between the last
+    /// message handed out from a sender and the first one held back, for every
+    /// sender with messages held back.
+    pub fn gaps(&self) -> Vec<(Uid, Range<u64>)> {
+        let mut gaps: Vec<(Uid, Range<u64>)> = self
+            .conversations
+            .iter()
+            .filter_map(|(&sender, conversation)| {
+                let first = *conversation.held.keys().next()?;
+                Some((sender, conversation.next..first))
+            })
+            .collect();
+        gaps.sort_by_key(|(sender, _)| *sender);
+        gaps
+    }
+}
+
+/// Blocking connection to a `sparta serve` instance.
+pub struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Ok(Connection {
+            stream: TcpStream::connect(addr)?,
+        })
+    }
+
+    /// Sends a request and waits for the round that serves it.
+    pub fn call(&mut self, request: &Request) -> io::Result<Response> {
+        sparta::write_frame(&mut self.stream, &request.encode())?;
+        Response::decode(&sparta::read_frame(&mut self.stream)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sparta::RecordType;
+
+    #[test]
+    fn test_roundtrip() {
+        let keys = UserKeys::generate();
+        let mut alice: Client<16> = Client::from_keys(&keys, 0);
+        let mut bob: Client<16> = Client::from_keys(&keys, 1);
+
+        let Request::Send(envelope) = alice.send(bob.uid(), &[7; 16]) else {
+            panic!("expected a send");
+        };
+        let record: Record<16> = envelope.open_send(&keys.key(alice.uid())).unwrap();
+        assert_eq!(record.uid, bob.uid());
+
+        bob.expect(1);
+        let dummy: Record<16> = Record::new(bob.uid(), RecordType::Dummy, 0, 0, 0);
+        let deliveries = vec![
+            Envelope::seal_delivery(&keys.key(bob.uid()), &record),
+            Envelope::seal_delivery(&keys.key(bob.uid()), &dummy),
+        ];
+        let messages = bob.open(&deliveries).unwrap();
+        assert_eq!(
+            messages,
+            vec![Message {
+                sender: alice.uid(),
+                seq: record.seq,
+                body: [7; 16]
+            }]
+        );
+        assert_eq!(bob.pending(), 0);
+        assert!(alice.open(&deliveries).is_err());
+    }
+
+    #[test]
+    fn test_ordering() {
+        let keys = UserKeys::generate();
+        let mut alice: Client<16> = Client::from_keys(&keys, 0);
+        let mut bob: Client<16> = Client::from_keys(&keys, 1);
+
+        let records: Vec<Record<16>> = (0..4u8)
+            .map(|i| {
+                let Request::Send(envelope) = alice.send(bob.uid(), &[i; 16]) else {
+                    panic!("expected a send");
+                };
+                envelope.open_send(&keys.key(alice.uid())).unwrap()
+            })
+            .collect();
+        let bodies = |messages: Vec<Message<16>>| -> Vec<u8> {
+            messages.iter().map(|message| message.body[0]).collect()
+        };
+
+        // the third message overtakes the second, the fourth is lost
+        assert_eq!(bodies(bob.receive(vec![records[0].clone()])), vec![0]);
+        assert_eq!(
+            bodies(bob.receive(vec![records[2].clone()])),
+            Vec::<u8>::new()
+        );
+        assert_eq!(
+            bob.gaps(),
+            vec![(alice.uid(), records[1].seq..records[2].seq)]
+        );
+
+        // a redelivered message is dropped
+        let delivered = bob.receive(vec![records[1].clone(), records[0].clone()]);
+        assert_eq!(bodies(delivered), vec![1, 2]);
+        assert!(bob.gaps().is_empty());
+        assert_eq!(bob.received(), 3);
+
+        let Request::GroupSend(envelope) = alice.send_to_group(7, &[9; 16]) else {
+            panic!("expected a group send");
+        };
+        let mut copy: Record<16> = envelope.open_send(&keys.key(alice.uid())).unwrap();
+        copy.uid = bob.uid();
+        assert_eq!(bodies(bob.receive(vec![copy])), vec![9]);
+    }
+
+    #[test]
+    fn test_gap_timeout() {
+        let keys = UserKeys::generate();
+        let mut alice: Client<16> = Client::from_keys(&keys, 0);
+        let mut bob: Client<16> = Client::from_keys(&keys, 1);
+        bob.gap_timeout = 2;
+
+        let recipient = bob.uid();
+        let send = |alice: &mut Client<16>, x: u8| -> Record<16> {
+            let Request::Send(envelope) = alice.send(recipient, &[x; 16]) else {
+                panic!("expected a send");
+            };
+            envelope.open_send(&keys.key(alice.uid())).unwrap()
+        };
+        let bodies = |messages: Vec<Message<16>>| -> Vec<u8> {
+            messages.iter().map(|message| message.body[0]).collect()
+        };
+
+        // the server refuses the first send, which is never sent again
+        send(&mut alice, 0);
+        let second = send(&mut alice, 1);
+        assert!(bodies(bob.receive(vec![second])).is_empty());
+        assert!(bodies(bob.receive(Vec::new())).is_empty());
+        assert_eq!(bodies(bob.receive(Vec::new())), vec![1]);
+        assert!(bob.gaps().is_empty());
+
+        // a restarted sender numbers its sends in a later session
+        let mut restarted: Client<16> = Client::from_keys(&keys, 0);
+        restarted.session = alice.session + 1;
+        let first = send(&mut restarted, 2);
+        assert_eq!(first.seq & COUNTER_MASK, 1);
+        assert_eq!(bodies(bob.receive(vec![first])), vec![2]);
+
+        // late messages of the old session are dropped
+        let late = send(&mut alice, 3);
+        assert!(bodies(bob.receive(vec![late])).is_empty());
+    }
+
+    #[test]
+    fn test_restart_with_gaps() {
+        let keys = UserKeys::generate();
+        let mut alice: Client<16> = Client::from_keys(&keys, 0);
+        let mut bob: Client<16> = Client::from_keys(&keys, 1);
+
+        let recipient = bob.uid();
+        let send = |alice: &mut Client<16>, x: u8| -> Record<16> {
+            let Request::Send(envelope) = alice.send(recipient, &[x; 16]) else {
+                panic!("expected a send");
+            };
+            envelope.open_send(&keys.key(alice.uid())).unwrap()
+        };
+        let bodies = |messages: Vec<Message<16>>| -> Vec<u8> {
+            messages.iter().map(|message| message.body[0]).collect()
+        };
+
+        // the first and third messages of the old session never arrive
+        let records: Vec<Record<16>> = (0..5).map(|x| send(&mut alice, x)).collect();
+        let arrived = vec![records[4].clone(), records[1].clone(), records[3].clone()];
+        assert!(bodies(bob.receive(arrived)).is_empty());
+        assert_eq!(bob.received(), 3);
+
+        let mut restarted: Client<16> = Client::from_keys(&keys, 0);
+        restarted.session = alice.session + 1;
+        let first = send(&mut restarted, 5);
+        assert_eq!(bodies(bob.receive(vec![first])), vec![1, 3, 4, 5]);
+        assert!(bob.gaps().is_empty());
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    env,
+    ffi::OsStr,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    process::{Child, Command, Stdio},
+};
+
+/// Submap processes started on this machine, each serving one submap on a
+/// loopback port. They are killed when the launcher is dropped.
+pub struct LocalSubmaps {
+    children: Vec<Child>,
+    addrs: Vec<String>,
+}
+
+impl LocalSubmaps {
+    /// Starts `num_submaps` copies of this binary in submap mode, each with
+    /// `num_threads` threads and the link key in `link_key`, and waits for
+    /// every one to report its address.
+    pub fn launch(num_submaps: usize, num_threads: usize, link_key: &Path) -> io::Result<Self> {
+        let exe = env::current_exe()?;
+        let mut submaps = LocalSubmaps {
+            children: Vec::with_capacity(num_submaps),
+            addrs: Vec::with_capacity(num_submaps),
+        };
+
+        for _ in 0..num_submaps {
+            let mut child = Command::new(&exe)
+                .args(["submap", "127.0.0.1:0", &num_threads.to_string()])
+                .args([OsStr::new("--link-key"), link_key.as_os_str()])
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let stdout = child.stdout.take().unwrap();
+            submaps.children.push(child);
+
+            let mut addr = String::new();
+            BufReader::new(stdout).read_line(&mut addr)?;
+            if addr.trim().is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "submap process exited before listening",
+                ));
+            }
+            submaps.addrs.push(addr.trim().to_string());
+        }
+        Ok(submaps)
+    }
+
+    pub fn addrs(&self) -> &[String] {
+        &self.addrs
+    }
+}
+
+impl Drop for LocalSubmaps {
+    fn drop(&mut self) {
+        for child in self.children.iter_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
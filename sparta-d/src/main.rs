@@ -1,19 +1,40 @@
-mod load_balancer;
-mod omap;
-mod record;
+mod launcher;
+mod network;
 
-use clap::Parser;
-use load_balancer::LoadBalancer;
-use record::Record;
-use std::time::UNIX_EPOCH;
-
-const RTT: f64 = 0.160; // 160ms
-const BPS: f64 = 125000000.0; //bytes per second
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use launcher::LocalSubmaps;
+use network::{NetworkModel, Topology};
+use sparta::{
+    LoadBalancer, MessageStore, Record, RemoteSubmap, ReplicatedSubmap, Result, Schedule,
+    SpartaError, Submap, UserKeys, DEFAULT_LAMBDA, DEFAULT_MESSAGE_SIZE, LINK_KEY_SIZE,
+};
+use std::{
+    fs,
+    io::{self, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
 
 /// Baseline oblivious sort based multiqueue.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    benchmark: Option<BenchmarkArgs>,
+}
+
+#[derive(clap::Args, Debug)]
+struct BenchmarkArgs {
     /// Number of send requests to store in the database.
     sends: usize,
 
@@ -36,47 +57,287 @@ struct Args {
     /// Number of runs before measurements are recorded.
     #[arg(short, long, default_value = "0")]
     warmup_runs: usize,
+
+    /// Statistical security parameter: batches reveal that they overflowed a
+    /// submap's padding with probability at most 2^-lambda.
+    #[arg(long, default_value_t = DEFAULT_LAMBDA)]
+    lambda: usize,
+
+    /// Run every submap in a process of its own on this machine instead of
+    /// simulating them in this one.
+    #[arg(short, long, conflicts_with = "submaps")]
+    local: bool,
+
+    /// Number of threads of each local submap process; defaults to `threads`.
+    #[arg(long, requires = "local")]
+    submap_threads: Option<usize>,
+
+    /// Comma-separated addresses of running submaps to distribute over instead
+    /// of simulating them; there must be `maps` times `replicas` of them, with
+    /// the replicas of a submap next to each other.
+    #[arg(long, value_delimiter = ',')]
+    submaps: Vec<String>,
+
+    /// Number of replicas of every distributed submap, each sent the same
+    /// batches, so that a run survives losing all replicas of a submap but one.
+    #[arg(long, default_value = "1")]
+    replicas: usize,
+
+    /// File holding the key that secures the links to distributed submaps;
+    /// created if missing.
+    #[arg(short = 'k', long, default_value = "link.key")]
+    link_key: PathBuf,
+
+    /// File of network parameters for simulated submaps, one `name value` pair
+    /// per line: rtt_ms, bandwidth, message_overhead and topology.
+    #[arg(short, long)]
+    network: Option<PathBuf>,
+
+    /// Round-trip time to a simulated submap in milliseconds [default: 160].
+    #[arg(long)]
+    rtt_ms: Option<f64>,
+
+    /// Bandwidth of a link in bytes per second [default: 125000000].
+    #[arg(long)]
+    bandwidth: Option<f64>,
+
+    /// Bytes of framing added to every record on the wire [default: 0].
+    #[arg(long)]
+    message_overhead: Option<usize>,
+
+    /// Whether simulated submaps share the load balancer's link (star) or each
+    /// have their own (links) [default: star].
+    #[arg(long, value_enum)]
+    topology: Option<Topology>,
+
+    /// Report the compute time and the network time of every run as separate
+    /// columns instead of their sum. Distributed runs measure the network
+    /// along with the compute time and report no network time.
+    #[arg(long)]
+    split: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve one submap to a load balancer over TCP.
+    Submap(SubmapArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct SubmapArgs {
+    /// Address to listen on; port 0 picks a free port.
+    addr: String,
+
+    /// Total number of threads available.
+    threads: usize,
+
+    /// File holding the key that secures the link to the load balancer;
+    /// created if missing.
+    #[arg(short = 'k', long, default_value = "link.key")]
+    link_key: PathBuf,
 }
 
 fn main() {
     let args = Args::parse();
 
-    let mut l = LoadBalancer::new(args.users as i64, args.threads, args.maps);
-    let sends: Vec<Record> = (0..args.sends)
-        .map(|x| Record::send(0 as i64, x.try_into().unwrap()))
+    let result = match (args.command, args.benchmark) {
+        (Some(Command::Submap(submap)), _) => run_submap(submap),
+        (None, Some(benchmark)) => run(benchmark),
+        (None, None) => Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the benchmark arguments are required without a subcommand",
+            )
+            .exit(),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn load_link_key(path: &Path) -> io::Result<[u8; LINK_KEY_SIZE]> {
+    match fs::read(path) {
+        Ok(key) => key
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad link key")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = sparta::generate_link_key();
+            fs::write(path, key)?;
+            Ok(key)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn run_submap(args: SubmapArgs) -> Result<()> {
+    let key = load_link_key(&args.link_key)?;
+    let listener = TcpListener::bind(&args.addr)?;
+    // the launcher reads the bound address off the first line
+    println!("{}", listener.local_addr()?);
+    io::stdout().flush()?;
+
+    sparta::serve_submap::<DEFAULT_MESSAGE_SIZE>(listener, args.threads, &key)
+}
+
+fn network_model(args: &BenchmarkArgs) -> Result<NetworkModel> {
+    let mut model = match &args.network {
+        Some(path) => NetworkModel::load(path)?,
+        None => NetworkModel::default(),
+    };
+    if let Some(rtt_ms) = args.rtt_ms {
+        model.rtt = rtt_ms / 1000.0;
+    }
+    if let Some(bandwidth) = args.bandwidth {
+        model.bandwidth = bandwidth;
+    }
+    if let Some(message_overhead) = args.message_overhead {
+        model.message_overhead = message_overhead;
+    }
+    if let Some(topology) = args.topology {
+        model.topology = topology;
+    }
+
+    if model.rtt.is_nan() || model.rtt < 0.0 {
+        return Err(SpartaError::InvalidConfig(
+            "round-trip time must not be negative",
+        ));
+    }
+    if model.bandwidth.is_nan() || model.bandwidth <= 0.0 {
+        return Err(SpartaError::InvalidConfig("bandwidth must be positive"));
+    }
+    Ok(model)
+}
+
+fn run(args: BenchmarkArgs) -> Result<()> {
+    let distributed = args.local || !args.submaps.is_empty();
+    let network_flags = args.network.is_some()
+        || args.rtt_ms.is_some()
+        || args.bandwidth.is_some()
+        || args.message_overhead.is_some()
+        || args.topology.is_some();
+    if distributed && network_flags {
+        return Err(SpartaError::InvalidConfig(
+            "distributed submaps use the real network, not a simulated one",
+        ));
+    }
+    let network = network_model(&args)?;
+
+    let key = if distributed {
+        load_link_key(&args.link_key)?
+    } else {
+        [0; LINK_KEY_SIZE]
+    };
+    let local = if args.local {
+        let submap_threads = args.submap_threads.unwrap_or(args.threads);
+        Some(LocalSubmaps::launch(
+            args.maps * args.replicas,
+            submap_threads,
+            &args.link_key,
+        )?)
+    } else {
+        None
+    };
+    let addrs = match &local {
+        Some(local) => local.addrs(),
+        None => &args.submaps[..],
+    };
+    if args.replicas == 0 {
+        return Err(SpartaError::InvalidConfig(
+            "at least one replica is required",
+        ));
+    }
+    if args.replicas > 1 && !distributed {
+        return Err(SpartaError::InvalidConfig(
+            "only distributed submaps can be replicated",
+        ));
+    }
+
+    let mut l: LoadBalancer<DEFAULT_MESSAGE_SIZE> = if distributed {
+        if addrs.len() != args.maps * args.replicas {
+            return Err(SpartaError::InvalidConfig(
+                "number of submap addresses does not match maps and replicas",
+            ));
+        }
+        let submaps = addrs
+            .chunks(args.replicas)
+            .map(|addrs| {
+                let replicas = addrs
+                    .iter()
+                    .map(|addr| {
+                        let replica = RemoteSubmap::connect(addr.as_str(), &key)?;
+                        Ok(Box::new(replica) as Box<dyn Submap<DEFAULT_MESSAGE_SIZE>>)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let submap: Box<dyn Submap<DEFAULT_MESSAGE_SIZE>> = match args.replicas {
+                    1 => replicas.into_iter().next().unwrap(),
+                    _ => Box::new(ReplicatedSubmap::new(replicas)?),
+                };
+                Ok(submap)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        LoadBalancer::with_submaps(args.users, args.threads, submaps, UserKeys::generate())?
+    } else {
+        // every submap stands in for a machine of its own, so the submaps run
+        // one after another and only the slowest counts towards the latency
+        LoadBalancer::with_schedule(
+            args.users,
+            args.threads,
+            args.maps,
+            UserKeys::generate(),
+            Schedule::Sequential,
+        )?
+    };
+    l.lambda = args.lambda;
+    eprintln!("send batch: {}", l.padding(args.sends));
+    eprintln!("fetch batch: {}", l.padding(args.fetches as usize));
+
+    let sends: Vec<Record<DEFAULT_MESSAGE_SIZE>> = (0..args.sends)
+        .map(|x| Record::send(0, sparta::message(x)))
         .collect();
 
-    l.batch_send(sends);
-    let mut net_size = 0;
+    MessageStore::batch_send(&mut l, sends)?;
 
-    let results: Vec<f64> = (0..(args.runs + args.warmup_runs))
+    let results = (0..(args.runs + args.warmup_runs))
         .map(|_| {
             let start = std::time::SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs_f64();
-            let (_responses, ns) = l.batch_fetch(vec![Record::fetch(0, args.fetches)]);
-            net_size = ns;
+            let _responses =
+                MessageStore::batch_fetch(&mut l, vec![Record::fetch(0, args.fetches)])?;
             let end = std::time::SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs_f64();
+            if distributed {
+                return Ok((end - start, 0.0));
+            }
 
-            // for response in responses.iter() {
-            //     println!("{:?}", response);
-            // }
+            let submap_times = l.submap_times();
+            let total: Duration = submap_times.iter().sum();
+            let slowest = submap_times.iter().max().copied().unwrap_or_default();
 
-            end - start + RTT + ((net_size * std::mem::size_of::<Record>() * 2) as f64 / BPS)
+            let compute = end - start - total.as_secs_f64() + slowest.as_secs_f64();
+            Ok((
+                compute,
+                network.cost(
+                    l.submap_size(),
+                    args.maps,
+                    Record::<DEFAULT_MESSAGE_SIZE>::ENCODED_SIZE,
+                ),
+            ))
         })
-        .collect();
+        .collect::<Result<Vec<(f64, f64)>>>()?;
 
     print!("{}\t", args.sends);
-    for result in results[..].iter() {
-        print!(
-            "{}\t",
-            *result,
-            // *result - RTT - ((net_size * std::mem::size_of::<Record>() * 2) as f64 / BPS)
-        );
+    for (compute, network) in results[..].iter() {
+        if args.split {
+            print!("{}\t{}\t", compute, network);
+        } else {
+            print!("{}\t", compute + network);
+        }
     }
     println!();
+    Ok(())
 }
@@ -0,0 +1,116 @@
+use clap::ValueEnum;
+use std::{fs, io, path::Path};
+
+/// How the submaps are wired to the load balancer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Topology {
+    /// All submap traffic shares the load balancer's one link.
+    Star,
+    /// Every submap has a link of its own to the load balancer.
+    Links,
+}
+
+/// Network between the load balancer and its submaps, used to model the time
+/// a batch spends on the wire when the submaps are simulated in one process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkModel {
+    /// Round-trip time in seconds.
+    pub rtt: f64,
+    /// Bandwidth of a single link in bytes per second.
+    pub bandwidth: f64,
+    /// Bytes of framing added to every record on the wire.
+    pub message_overhead: usize,
+    pub topology: Topology,
+}
+
+impl Default for NetworkModel {
+    /// 160ms round trips over a shared 1 Gbit/s link.
+    fn default() -> Self {
+        NetworkModel {
+            rtt: 0.160,
+            bandwidth: 125000000.0,
+            message_overhead: 0,
+            topology: Topology::Star,
+        }
+    }
+}
+
+impl NetworkModel {
+    /// Reads a model from a file with one `name value` pair per line, for
+    /// `rtt_ms`, `bandwidth`, `message_overhead` and `topology`. Parameters the
+    /// file leaves out keep their default; `#` starts a comment.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let mut model = NetworkModel::default();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let (Some(name), Some(value), None) = (words.next(), words.next(), words.next()) else {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                return Err(invalid(format!("bad network parameter: {}", line.trim())));
+            };
+
+            let bad_value = || invalid(format!("bad value for {}: {}", name, value));
+            match name {
+                "rtt_ms" => model.rtt = value.parse::<f64>().map_err(|_| bad_value())? / 1000.0,
+                "bandwidth" => model.bandwidth = value.parse().map_err(|_| bad_value())?,
+                "message_overhead" => {
+                    model.message_overhead = value.parse().map_err(|_| bad_value())?
+                }
+                "topology" => {
+                    model.topology = Topology::from_str(value, true).map_err(|_| bad_value())?
+                }
+                _ => return Err(invalid(format!("unknown network parameter: {}", name))),
+            }
+        }
+        Ok(model)
+    }
+
+    /// Time to send `records` records of `record_size` bytes to each of
+    /// `num_submaps` submaps and receive as many back, one round trip per batch.
+    pub fn cost(&self, records: usize, num_submaps: usize, record_size: usize) -> f64 {
+        let link_bytes = 2 * records * (record_size + self.message_overhead);
+        let bytes = match self.topology {
+            Topology::Star => link_bytes * num_submaps,
+            Topology::Links => link_bytes,
+        };
+        self.rtt + bytes as f64 / self.bandwidth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost() {
+        let mut model = NetworkModel {
+            rtt: 0.1,
+            bandwidth: 1000.0,
+            message_overhead: 10,
+            topology: Topology::Star,
+        };
+        assert_eq!(model.cost(5, 4, 90), 0.1 + 4.0);
+
+        model.topology = Topology::Links;
+        assert_eq!(model.cost(5, 4, 90), 0.1 + 1.0);
+    }
+
+    #[test]
+    fn test_load() {
+        let path =
+            std::env::temp_dir().join(format!("sparta-d-network-test-{}", std::process::id()));
+        fs::write(&path, "# lan\nrtt_ms 2\n\ntopology links\n").unwrap();
+        let model = NetworkModel::load(&path).unwrap();
+        assert_eq!(model.rtt, 0.002);
+        assert_eq!(model.topology, Topology::Links);
+        assert_eq!(model.bandwidth, NetworkModel::default().bandwidth);
+
+        fs::write(&path, "latency 2\n").unwrap();
+        assert!(NetworkModel::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+use crate::record::{select_uid, Record, RecordType, Uid};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use otils::ObliviousOps;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+
+const SEND: u8 = 0;
+const DELIVERY: u8 = 1;
+const FETCH: u8 = 2;
+const ACK: u8 = 3;
+
+/// Per-user envelope keys, derived from a single master secret so the load
+/// balancer never has to look a key up by user id.
+#[derive(Clone)]
+pub struct UserKeys {
+    master: [u8; KEY_SIZE],
+}
+
+impl UserKeys {
+    pub fn new(master: [u8; KEY_SIZE]) -> Self {
+        UserKeys { master }
+    }
+
+    pub fn generate() -> Self {
+        UserKeys::new(ChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    pub fn master(&self) -> &[u8; KEY_SIZE] {
+        &self.master
+    }
+
+    pub fn key(&self, uid: Uid) -> [u8; KEY_SIZE] {
+        blake3::keyed_hash(&self.master, &uid.to_le_bytes()).into()
+    }
+}
+
+/// A message sealed under a single user's key. Sends are sealed by the sender
+/// and carry the recipient inside the ciphertext; deliveries are sealed for the
+/// recipient and hide whether they hold a real message or dummy fill.
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    pub uid: Uid,
+    pub nonce: [u8; NONCE_SIZE],
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    fn aad(kind: u8, uid: Uid) -> [u8; 17] {
+        let mut aad = [kind; 17];
+        aad[1..].copy_from_slice(&uid.to_le_bytes());
+        aad
+    }
+
+    fn seal(key: &[u8; KEY_SIZE], kind: u8, uid: Uid, plaintext: &[u8]) -> Self {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &Envelope::aad(kind, uid),
+        };
+        let ciphertext = cipher.encrypt(&nonce, payload).unwrap();
+
+        Envelope {
+            uid,
+            nonce: nonce.into(),
+            ciphertext,
+        }
+    }
+
+    fn open(&self, key: &[u8; KEY_SIZE], kind: u8) -> Option<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let payload = Payload {
+            msg: &self.ciphertext[..],
+            aad: &Envelope::aad(kind, self.uid),
+        };
+        cipher.decrypt(Nonce::from_slice(&self.nonce), payload).ok()
+    }
+
+    /// Padding envelope that never opens; the load balancer stores it as a
+    /// padding send whatever its id.
+    pub fn dummy() -> Self {
+        Envelope {
+            uid: 0,
+            nonce: [0; NONCE_SIZE],
+            ciphertext: Vec::new(),
+        }
+    }
+
+    /// Send of `message` to `recipient`, numbered `seq` in the sender's
+    /// conversation with the recipient. Sequence numbers start at 1; 0 leaves
+    /// the message unnumbered.
+    pub fn seal_send<const N: usize>(
+        key: &[u8; KEY_SIZE],
+        sender: Uid,
+        recipient: Uid,
+        seq: u64,
+        message: &[u8; N],
+    ) -> Self {
+        let mut plaintext = Vec::with_capacity(24 + N);
+        plaintext.extend_from_slice(&recipient.to_le_bytes());
+        plaintext.extend_from_slice(&seq.to_le_bytes());
+        plaintext.extend_from_slice(message);
+        Envelope::seal(key, SEND, sender, &plaintext)
+    }
+
+    /// Opens a send, stamping it with the sender the envelope authenticates.
+    pub fn open_send<const N: usize>(&self, key: &[u8; KEY_SIZE]) -> Option<Record<N>> {
+        let plaintext = self.open(key, SEND)?;
+        if plaintext.len() != 24 + N {
+            return None;
+        }
+
+        let recipient = Uid::from_le_bytes(plaintext[..16].try_into().unwrap());
+        let mut record = Record::send(recipient, plaintext[24..].try_into().unwrap());
+        record.sender = self.uid;
+        record.seq = u64::from_le_bytes(plaintext[16..24].try_into().unwrap());
+        Some(record)
+    }
+
+    /// Fetch of `volume` messages for `uid`. The stamp must grow with every
+    /// fetch of the user, so that a fetch seen on the wire cannot be replayed
+    /// to drain the user's messages.
+    pub fn seal_fetch(key: &[u8; KEY_SIZE], uid: Uid, stamp: u64, volume: u64) -> Self {
+        let mut plaintext = Vec::with_capacity(16);
+        plaintext.extend_from_slice(&stamp.to_le_bytes());
+        plaintext.extend_from_slice(&volume.to_le_bytes());
+        Envelope::seal(key, FETCH, uid, &plaintext)
+    }
+
+    /// Opens a fetch, returning its stamp and volume.
+    pub fn open_fetch(&self, key: &[u8; KEY_SIZE]) -> Option<(u64, u64)> {
+        let plaintext = self.open(key, FETCH)?;
+        if plaintext.len() != 16 {
+            return None;
+        }
+        Some((
+            u64::from_le_bytes(plaintext[..8].try_into().unwrap()),
+            u64::from_le_bytes(plaintext[8..].try_into().unwrap()),
+        ))
+    }
+
+    /// Acknowledgement by `uid` of the deliveries of its fetch stamped `stamp`.
+    pub fn seal_ack(key: &[u8; KEY_SIZE], uid: Uid, stamp: u64) -> Self {
+        Envelope::seal(key, ACK, uid, &stamp.to_le_bytes())
+    }
+
+    /// Opens an acknowledgement, returning the stamp of the fetch it names.
+    pub fn open_ack(&self, key: &[u8; KEY_SIZE]) -> Option<u64> {
+        let plaintext = self.open(key, ACK)?;
+        Some(u64::from_le_bytes(plaintext.try_into().ok()?))
+    }
+
+    pub fn seal_delivery<const N: usize>(key: &[u8; KEY_SIZE], record: &Record<N>) -> Self {
+        let is_send = record.is_send();
+        let mut plaintext = Vec::with_capacity(25 + N);
+        plaintext.push(is_send as u8);
+        plaintext.extend_from_slice(&select_uid(is_send, record.sender, 0).to_le_bytes());
+        plaintext.extend_from_slice(&u64::oselect(is_send, record.seq, 0).to_le_bytes());
+        plaintext.extend_from_slice(&record.message);
+        Envelope::seal(key, DELIVERY, record.uid, &plaintext)
+    }
+
+    pub fn open_delivery<const N: usize>(&self, key: &[u8; KEY_SIZE]) -> Option<Record<N>> {
+        let plaintext = self.open(key, DELIVERY)?;
+        if plaintext.len() != 25 + N {
+            return None;
+        }
+
+        let rec_type = match plaintext[0] {
+            1 => RecordType::Send,
+            _ => RecordType::Dummy,
+        };
+        let mut record = Record::new(self.uid, rec_type, 0, 0, 0);
+        record.sender = Uid::from_le_bytes(plaintext[1..17].try_into().unwrap());
+        record.seq = u64::from_le_bytes(plaintext[17..25].try_into().unwrap());
+        record.message.copy_from_slice(&plaintext[25..]);
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send() {
+        let keys = UserKeys::generate();
+        let envelope = Envelope::seal_send(&keys.key(1), 1, 2, 5, &[7; 16]);
+
+        let record: Record<16> = envelope.open_send(&keys.key(1)).unwrap();
+        assert_eq!(record.uid, 2);
+        assert!(record.is_send());
+        assert_eq!((record.sender, record.seq), (1, 5));
+        assert_eq!(record.message, [7; 16]);
+
+        assert!(envelope.open_send::<16>(&keys.key(2)).is_none());
+        assert!(envelope.open_delivery::<16>(&keys.key(1)).is_none());
+    }
+
+    #[test]
+    fn test_delivery() {
+        let keys = UserKeys::generate();
+        let mut send: Record<16> = Record::send(3, [9; 16]);
+        send.sender = 4;
+        send.seq = 2;
+        let mut dummy: Record<16> = Record::new(3, RecordType::Dummy, 0, 0, 0);
+        dummy.sender = 4;
+
+        let record: Record<16> = Envelope::seal_delivery(&keys.key(3), &send)
+            .open_delivery(&keys.key(3))
+            .unwrap();
+        assert!(record.is_send());
+        assert_eq!((record.sender, record.seq), (4, 2));
+        assert_eq!(record.message, [9; 16]);
+
+        let record: Record<16> = Envelope::seal_delivery(&keys.key(3), &dummy)
+            .open_delivery(&keys.key(3))
+            .unwrap();
+        assert_eq!(record.rec_type, RecordType::Dummy);
+        assert_eq!(record.sender, 0);
+    }
+
+    #[test]
+    fn test_tamper() {
+        let keys = UserKeys::generate();
+        let mut envelope = Envelope::seal_send(&keys.key(1), 1, 2, 1, &[7; 16]);
+        envelope.ciphertext[0] ^= 1;
+        assert!(envelope.open_send::<16>(&keys.key(1)).is_none());
+
+        let mut envelope = Envelope::seal_send(&keys.key(1), 1, 2, 1, &[7; 16]);
+        envelope.uid = 2;
+        assert!(envelope.open_send::<16>(&keys.key(1)).is_none());
+
+        let envelope = Envelope::seal_fetch(&keys.key(1), 1, 3, 8);
+        assert_eq!(envelope.open_fetch(&keys.key(1)), Some((3, 8)));
+        assert!(envelope.open_fetch(&keys.key(2)).is_none());
+        assert!(envelope.open_send::<16>(&keys.key(1)).is_none());
+
+        let envelope = Envelope::seal_ack(&keys.key(1), 1, 3);
+        assert_eq!(envelope.open_ack(&keys.key(1)), Some(3));
+        assert!(envelope.open_ack(&keys.key(2)).is_none());
+        assert!(envelope.open_fetch(&keys.key(1)).is_none());
+    }
+}
@@ -0,0 +1,79 @@
+use rayon::ThreadPoolBuildError;
+use std::{error, fmt, io};
+
+/// Errors returned by the load balancer and the message stores.
+#[derive(Debug)]
+pub enum SpartaError {
+    /// Reading or writing a snapshot failed, or its contents did not
+    /// authenticate or decode.
+    Io(io::Error),
+    /// A snapshot holds messages of a different size than the load balancer
+    /// restoring it.
+    MessageSizeMismatch { expected: usize, found: usize },
+    /// More submaps than a record's map field can address.
+    TooManySubmaps { num_submaps: usize, max: usize },
+    /// A configuration parameter is out of range.
+    InvalidConfig(&'static str),
+    /// A request in a batch is malformed.
+    InvalidRequest(&'static str),
+    /// A worker thread pool could not be started.
+    ThreadPool(ThreadPoolBuildError),
+    /// A submap panicked while running its part of a batch. The batch was
+    /// rolled back.
+    SubmapFailed { submap: usize, reason: String },
+    /// A submap could not commit a batch that the submaps before it had
+    /// already committed, so the submaps no longer agree. The load balancer
+    /// refuses every later batch and has to be restored from a snapshot.
+    CommitFailed { submap: usize, reason: String },
+}
+
+pub type Result<T> = std::result::Result<T, SpartaError>;
+
+impl fmt::Display for SpartaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpartaError::Io(e) => write!(f, "{}", e),
+            SpartaError::MessageSizeMismatch { expected, found } => write!(
+                f,
+                "message size mismatch: expected {} bytes, found {}",
+                expected, found
+            ),
+            SpartaError::TooManySubmaps { num_submaps, max } => write!(
+                f,
+                "{} submaps requested, at most {} supported",
+                num_submaps, max
+            ),
+            SpartaError::InvalidConfig(msg) => write!(f, "invalid configuration: {}", msg),
+            SpartaError::InvalidRequest(msg) => write!(f, "invalid request: {}", msg),
+            SpartaError::ThreadPool(e) => write!(f, "thread pool: {}", e),
+            SpartaError::SubmapFailed { submap, reason } => {
+                write!(f, "submap {} failed: {}", submap, reason)
+            }
+            SpartaError::CommitFailed { submap, reason } => {
+                write!(f, "submap {} failed to commit: {}", submap, reason)
+            }
+        }
+    }
+}
+
+impl error::Error for SpartaError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SpartaError::Io(e) => Some(e),
+            SpartaError::ThreadPool(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SpartaError {
+    fn from(e: io::Error) -> Self {
+        SpartaError::Io(e)
+    }
+}
+
+impl From<ThreadPoolBuildError> for SpartaError {
+    fn from(e: ThreadPoolBuildError) -> Self {
+        SpartaError::ThreadPool(e)
+    }
+}
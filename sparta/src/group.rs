@@ -0,0 +1,261 @@
+use crate::error::{Result, SpartaError};
+use crate::record::{select_uid, IndexRecord, Record, RecordType, Uid};
+use otils::{Max, ObliviousOps};
+use rayon::ThreadPool;
+use std::{cmp::Ordering, collections::HashSet, io};
+
+/// Largest group a send can fan out to unless configured otherwise.
+pub const DEFAULT_MAX_GROUP_SIZE: usize = 16;
+
+/// Row of the group table or one copy of a group send, keyed by group id and
+/// member slot. A row holds its member as a user store record, padding for an
+/// empty slot; a copy holds the send it was made from.
+struct GroupRecord<const N: usize> {
+    padding: bool,
+    gid: Uid,
+    slot: u32,
+    record: Record<N>,
+}
+
+impl<const N: usize> GroupRecord<N> {
+    fn row(gid: Uid, slot: u32, member: Option<Uid>) -> Self {
+        let mut record = Record::new(member.unwrap_or(Uid::MAX), RecordType::User, 0, 0, 0);
+        record.padding = member.is_none();
+        GroupRecord {
+            padding: false,
+            gid,
+            slot,
+            record,
+        }
+    }
+
+    fn copy(send: &Record<N>, slot: u32) -> Self {
+        let mut record = Record::new(send.uid, send.rec_type.clone(), 0, 0, 0);
+        record.padding = send.padding;
+        record.position = send.position;
+        record.sender = send.sender;
+        record.seq = send.seq;
+        record.message = send.message;
+        GroupRecord {
+            padding: send.padding,
+            gid: send.uid,
+            slot,
+            record,
+        }
+    }
+}
+
+impl<const N: usize> PartialEq for GroupRecord<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.padding == other.padding
+            && self.gid == other.gid
+            && self.slot == other.slot
+            && self.record.rec_type == other.record.rec_type
+    }
+}
+
+impl<const N: usize> PartialOrd for GroupRecord<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let key = (self.padding, self.gid, self.slot);
+        let other_key = (other.padding, other.gid, other.slot);
+        match key.partial_cmp(&other_key) {
+            Some(Ordering::Equal) => self.record.rec_type.partial_cmp(&other.record.rec_type),
+            x => x,
+        }
+    }
+}
+
+impl<const N: usize> Max for GroupRecord<N> {
+    fn maximum() -> Self {
+        GroupRecord {
+            padding: true,
+            gid: Uid::MAX,
+            slot: u32::MAX,
+            record: Record::padding(RecordType::Dummy, 0, 0),
+        }
+    }
+}
+
+/// Group membership kept alongside the user store. Every group occupies
+/// exactly `max_group_size` slots, empty ones holding padding, and every
+/// group send fans out to that many copies, so neither the table nor a batch
+/// reveals the size of a group.
+pub struct GroupTable<const N: usize> {
+    max_group_size: usize,
+    rows: Vec<(Uid, Option<Uid>)>,
+}
+
+impl<const N: usize> GroupTable<N> {
+    pub fn new(max_group_size: usize) -> Self {
+        GroupTable {
+            max_group_size,
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn max_group_size(&self) -> usize {
+        self.max_group_size
+    }
+
+    /// Replaces the members of `gid`. Membership changes are administrative and
+    /// not hidden from the host.
+    pub fn set(&mut self, gid: Uid, members: &[Uid]) -> Result<()> {
+        if members.len() > self.max_group_size {
+            return Err(SpartaError::InvalidRequest(
+                "group larger than max group size",
+            ));
+        }
+
+        self.remove(gid);
+        self.rows
+            .extend(members.iter().map(|&member| (gid, Some(member))));
+        self.rows
+            .extend((members.len()..self.max_group_size).map(|_| (gid, None)));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, gid: Uid) {
+        self.rows.retain(|(g, _)| *g != gid);
+    }
+
+    /// Groups as (gid, member) rows, empty slots included.
+    pub fn rows(&self) -> &[(Uid, Option<Uid>)] {
+        &self.rows
+    }
+
+    /// Table holding `rows`, as returned by `rows`. `expand` relies on every
+    /// group filling one contiguous block of `max_group_size` rows, so rows
+    /// that do not form such blocks are rejected.
+    pub fn from_rows(max_group_size: usize, rows: Vec<(Uid, Option<Uid>)>) -> Result<Self> {
+        let mut gids = HashSet::new();
+        let is_valid = match max_group_size {
+            0 => rows.is_empty(),
+            _ => {
+                rows.len().is_multiple_of(max_group_size)
+                    && rows.chunks(max_group_size).all(|block| {
+                        let gid = block[0].0;
+                        block.iter().all(|(g, _)| *g == gid) && gids.insert(gid)
+                    })
+            }
+        };
+        if !is_valid {
+            return Err(SpartaError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "group rows are not one block of max group size per group",
+            )));
+        }
+        Ok(GroupTable {
+            max_group_size,
+            rows,
+        })
+    }
+
+    /// Expands sends addressed to group ids into `max_group_size` sends each,
+    /// one per member slot. Copies for empty slots or unknown groups become
+    /// dummy sends.
+    pub(crate) fn expand(
+        &self,
+        sends: Vec<Record<N>>,
+        pool: &ThreadPool,
+        num_threads: usize,
+    ) -> Vec<IndexRecord<N>> {
+        let num_copies = sends.len() * self.max_group_size;
+
+        let mut records: Vec<GroupRecord<N>> = Vec::with_capacity(self.rows.len() + num_copies);
+        for (slot, (gid, member)) in self.rows.iter().enumerate() {
+            let slot = (slot % self.max_group_size) as u32;
+            records.push(GroupRecord::row(*gid, slot, *member));
+        }
+        for send in sends.iter() {
+            records
+                .extend((0..self.max_group_size).map(|slot| GroupRecord::copy(send, slot as u32)));
+        }
+
+        // each table row sorts directly ahead of the copies for its slot
+        let mut records = otils::sort(records, pool, num_threads);
+
+        let mut member = Uid::MAX;
+        let mut has_member = false;
+        let mut prev = (true, Uid::MAX, u32::MAX);
+        for r in records.iter_mut() {
+            let is_row = r.record.is_user_store();
+            let is_same_slot = (r.padding, r.gid, r.slot) == prev;
+            prev = (r.padding, r.gid, r.slot);
+
+            member = select_uid(is_row, r.record.uid, member);
+            has_member = u8::oselect(
+                is_row,
+                !r.record.padding as u8,
+                u8::oselect(is_same_slot, has_member as u8, 0),
+            ) == 1;
+
+            let rec_type = u8::oselect(
+                has_member,
+                r.record.rec_type.clone() as u8,
+                RecordType::Dummy as u8,
+            );
+            r.record.rec_type =
+                RecordType::from_u8(u8::oselect(is_row, RecordType::User as u8, rec_type)).unwrap();
+            r.record.uid = select_uid(is_row, r.record.uid, member);
+            r.record.padding = u8::oselect(is_row || has_member, r.record.padding as u8, 1) == 1;
+        }
+
+        otils::compact(
+            &mut records[..],
+            |r| !r.record.is_user_store(),
+            pool,
+            num_threads,
+        );
+        records.truncate(num_copies);
+        records.into_iter().map(|r| IndexRecord(r.record)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let mut groups: GroupTable<4> = GroupTable::new(3);
+        groups.set(10, &[1, 2]).unwrap();
+        groups.set(20, &[3, 4, 5]).unwrap();
+        assert!(groups.set(30, &[1, 2, 3, 4]).is_err());
+
+        let sends = vec![
+            Record::send(10, [1; 4]),
+            Record::send(20, [2; 4]),
+            Record::send(30, [3; 4]),
+        ];
+        let copies = groups.expand(sends, &pool, 1);
+        assert_eq!(copies.len(), 9);
+
+        let mut delivered: Vec<(Uid, u8)> = copies
+            .iter()
+            .filter(|r| r.0.is_send())
+            .map(|r| (r.0.uid, r.0.message[0]))
+            .collect();
+        delivered.sort();
+        assert_eq!(delivered, vec![(1, 1), (2, 1), (3, 2), (4, 2), (5, 2)]);
+    }
+
+    #[test]
+    fn test_from_rows() {
+        let mut groups: GroupTable<4> = GroupTable::new(2);
+        groups.set(10, &[1]).unwrap();
+        groups.set(20, &[2, 3]).unwrap();
+        let rows = groups.rows().to_vec();
+        assert!(GroupTable::<4>::from_rows(2, rows.clone()).is_ok());
+
+        assert!(GroupTable::<4>::from_rows(2, rows[1..].to_vec()).is_err());
+        assert!(GroupTable::<4>::from_rows(4, rows.clone()).is_err());
+        let interleaved = vec![rows[0], rows[2], rows[1], rows[3]];
+        assert!(GroupTable::<4>::from_rows(2, interleaved).is_err());
+        let repeated = [&rows[..2], &rows[..2]].concat();
+        assert!(GroupTable::<4>::from_rows(2, repeated).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! Sparta message stores and the types shared with their clients: records,
+//! sealed envelopes and the wire protocol spoken by `sparta serve`. Messages
+//! are stored through the `MessageStore` trait, backed by the load balancer
+//! over its submaps or by a single oblivious multiqueue.
+//!
+//! ```
+//! use sparta::{Backend, MessageStore, Record};
+//!
+//! # fn main() -> sparta::Result<()> {
+//! let mut store = Backend::Parallel.open::<16>(4, 6, 2)?;
+//! store.batch_send(vec![Record::send(1, [7; 16])])?;
+//!
+//! // fetches are always answered with their full volume, padded with dummies
+//! let delivered = store.batch_fetch(vec![Record::fetch(1, 2)])?;
+//! assert_eq!(delivered.len(), 2);
+//! assert_eq!(delivered.iter().filter(|r| r.is_send()).count(), 1);
+//! # Ok(())
+//! # }
+//! ```
+
+mod codec;
+mod envelope;
+mod error;
+mod group;
+mod load_balancer;
+mod omap;
+mod omq;
+mod pending;
+mod prf;
+mod protocol;
+mod record;
+mod snapshot;
+mod store;
+mod submap;
+
+pub use envelope::{Envelope, UserKeys, KEY_SIZE};
+pub use error::{Result, SpartaError};
+pub use group::{GroupTable, DEFAULT_MAX_GROUP_SIZE};
+pub use load_balancer::{LoadBalancer, Padding, Schedule, DEFAULT_LAMBDA, MAX_SUBMAPS};
+pub use omap::{ObliviousMap, Retention};
+pub use omq::ObliviousMultiQueue;
+pub use protocol::{read_frame, write_frame, Request, Response, MAX_FRAME_SIZE};
+pub use record::{message, Record, RecordType, Uid, DEFAULT_MESSAGE_SIZE};
+pub use snapshot::SEALING_KEY_SIZE;
+pub use store::{Backend, MessageStore, MAX_FETCH_VOLUME};
+pub use submap::{
+    generate_link_key, serve as serve_submap, RemoteSubmap, ReplicatedSubmap, Submap, LINK_KEY_SIZE,
+};
@@ -1,104 +1,395 @@
-use crate::omap::ObliviousMap;
-pub use crate::record::{IndexRecord, Record, RecordType, SubmapRecord};
+use crate::codec::Decoder;
+use crate::envelope::{Envelope, UserKeys};
+use crate::error::{Result, SpartaError};
+use crate::group::{GroupTable, DEFAULT_MAX_GROUP_SIZE};
+use crate::omap::{ObliviousMap, Retention};
+use crate::pending::PendingDeliveries;
+use crate::prf::Prf;
+use crate::record::{IndexRecord, Record, RecordType, SubmapRecord, Uid};
+use crate::snapshot::{self, SEALING_KEY_SIZE};
+use crate::store::{validate_fetches, validate_sends, MessageStore};
+use crate::submap::Submap;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
 use fastapprox::fast;
-use otils::{self, ObliviousOps};
+use otils::{self, Max, ObliviousOps};
 use rayon::ThreadPool;
 use std::{
+    any::Any,
     cmp,
-    f64::consts::E,
-    sync::{Arc, Mutex},
-    // time::UNIX_EPOCH,
+    collections::VecDeque,
+    f64::consts::{E, LN_2},
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
 };
 
-const LAMBDA: usize = 128;
+/// Statistical security parameter a load balancer pads with unless configured
+/// otherwise.
+pub const DEFAULT_LAMBDA: usize = 128;
 
-pub struct LoadBalancer {
-    num_users: i64,
+/// Most submaps a load balancer can run. Submaps are addressed by a `u32`
+/// and `u32::MAX` marks the end of the submap requests.
+pub const MAX_SUBMAPS: usize = u32::MAX as usize;
+
+/// Principal branch of the Lambert W function, rounded up so that the padding
+/// bound never undershoots. The fast approximation is too coarse for the large
+/// arguments many submaps produce and near the branch point at -1/e, where
+/// large batches land, so it is refined with Newton steps, starting from the
+/// series around the branch point there.
+fn lambertw(x: f64) -> f64 {
+    let mut w = if x < -0.25 {
+        let p = (2_f64 * (E * x + 1_f64)).max(0_f64).sqrt();
+        -1_f64 + p - p * p / 3_f64 + 11_f64 / 72_f64 * p * p * p
+    } else {
+        fast::lambertw(x as f32) as f64
+    };
+    for _ in 0..4 {
+        let ew = E.powf(w);
+        let step = (w * ew - x) / (ew * (w + 1_f64));
+        if !step.is_finite() {
+            break;
+        }
+        w -= step;
+    }
+    // w e^w grows with w, so stepping up until it reaches x leaves w at or
+    // just above W(x) whichever way Newton rounded
+    while w * E.powf(w) < x {
+        w = w.next_up();
+    }
+    w
+}
+
+fn panic_reason(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(reason) => *reason,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(reason) => reason.to_string(),
+            Err(_) => "panicked".to_string(),
+        },
+    }
+}
+
+/// Whether the send at `position` in a batch was stored or dropped by the
+/// recipient's quota.
+struct SendStatus {
+    position: u64,
+    stored: bool,
+}
+
+impl PartialEq for SendStatus {
+    fn eq(&self, other: &Self) -> bool {
+        self.position == other.position
+    }
+}
+
+impl PartialOrd for SendStatus {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        self.position.partial_cmp(&other.position)
+    }
+}
+
+impl Max for SendStatus {
+    fn maximum() -> Self {
+        SendStatus {
+            position: u64::MAX,
+            stored: false,
+        }
+    }
+}
+
+/// How a batch is padded on its way to the submaps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Padding {
+    pub num_requests: usize,
+    /// Requests, real and dummy, every submap gets.
+    pub submap_size: usize,
+    /// Dummies across all submaps.
+    pub dummies: usize,
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requests padded to {} per submap, {} dummies ({:.2}x)",
+            self.num_requests,
+            self.submap_size,
+            self.dummies,
+            (self.num_requests + self.dummies) as f64 / self.num_requests.max(1) as f64
+        )
+    }
+}
+
+/// How the submaps of a batch are run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Schedule {
+    /// All submaps at once, each on its share of the threads or in a process
+    /// of its own.
+    Parallel,
+    /// One submap after another, each on all the threads, as each submap
+    /// would run on its own machine in a distributed deployment.
+    Sequential,
+}
+
+pub struct LoadBalancer<const N: usize> {
+    num_users: usize,
     num_submaps: usize,
     num_threads: usize,
+    // threads split between the load balancer and submaps it runs in parallel
+    // in this process, if it created them
+    thread_budget: Option<usize>,
+    schedule: Schedule,
+    submap_times: Vec<Duration>,
+    submap_size: usize,
 
     pool: ThreadPool,
-    pub user_store: Vec<IndexRecord>,
-    pub submaps: Vec<ObliviousMap>,
+    keys: UserKeys,
+    prf: Prf,
+    round: u64,
+    pub retention: Retention,
+    pub quota: Option<u32>,
+    /// Statistical security parameter: a batch overflows the padding of some
+    /// submap, revealing that it did, with probability at most 2^-lambda.
+    /// Lower values pad with fewer dummies.
+    pub lambda: usize,
+    /// Rounds a delivery waits for its recipient to acknowledge it before it
+    /// is delivered again, at least one; `None` makes every delivery final.
+    pub ack_timeout: Option<u64>,
+    pending: PendingDeliveries<N>,
+    // sends stored per round, real and dummy; their sum bounds the messages
+    // still live in the submaps as a function of the batch sizes alone
+    stored_sends: VecDeque<(u64, usize)>,
+    user_store: Vec<IndexRecord<N>>,
+    pub groups: GroupTable<N>,
+    submaps: Vec<Box<dyn Submap<N>>>,
+    // submap whose failed commit left the submaps disagreeing, and why
+    failed_commit: Option<(usize, String)>,
 }
 
-impl LoadBalancer {
-    pub fn new(num_users: i64, num_threads: usize, num_submaps: usize) -> Self {
-        let component_threads = num_threads / (num_submaps + 1);
+impl<const N: usize> LoadBalancer<N> {
+    pub fn new(
+        num_users: usize,
+        num_threads: usize,
+        num_submaps: usize,
+        keys: UserKeys,
+    ) -> Result<Self> {
+        LoadBalancer::with_schedule(
+            num_users,
+            num_threads,
+            num_submaps,
+            keys,
+            Schedule::Parallel,
+        )
+    }
+
+    /// Load balancer for users `0..num_users` over `num_submaps` submaps. With
+    /// the parallel schedule the threads are split evenly between the load
+    /// balancer and its submaps, so there must be at least one per component.
+    pub fn with_schedule(
+        num_users: usize,
+        num_threads: usize,
+        num_submaps: usize,
+        keys: UserKeys,
+        schedule: Schedule,
+    ) -> Result<Self> {
+        if num_submaps == 0 {
+            return Err(SpartaError::InvalidConfig(
+                "at least one submap is required",
+            ));
+        }
+        if num_submaps > MAX_SUBMAPS {
+            return Err(SpartaError::TooManySubmaps {
+                num_submaps,
+                max: MAX_SUBMAPS,
+            });
+        }
+        let component_threads = match schedule {
+            Schedule::Parallel => num_threads / (num_submaps + 1),
+            Schedule::Sequential => num_threads,
+        };
+        if component_threads == 0 {
+            return Err(SpartaError::InvalidConfig(
+                "too few threads for the load balancer and its submaps",
+            ));
+        }
+        let mut submaps: Vec<Box<dyn Submap<N>>> = Vec::with_capacity(num_submaps);
+        for _ in 0..num_submaps {
+            submaps.push(Box::new(ObliviousMap::new(component_threads)?));
+        }
+        let mut l =
+            LoadBalancer::from_submaps(num_users, component_threads, submaps, keys, schedule)?;
+        if schedule == Schedule::Parallel {
+            l.thread_budget = Some(num_threads);
+        }
+        Ok(l)
+    }
+
+    /// Load balancer for users `0..num_users` over submaps running elsewhere,
+    /// such as `RemoteSubmap`s in processes of their own. The submaps run in
+    /// parallel and all `num_threads` threads go to the load balancer.
+    pub fn with_submaps(
+        num_users: usize,
+        num_threads: usize,
+        submaps: Vec<Box<dyn Submap<N>>>,
+        keys: UserKeys,
+    ) -> Result<Self> {
+        if submaps.is_empty() {
+            return Err(SpartaError::InvalidConfig(
+                "at least one submap is required",
+            ));
+        }
+        if submaps.len() > MAX_SUBMAPS {
+            return Err(SpartaError::TooManySubmaps {
+                num_submaps: submaps.len(),
+                max: MAX_SUBMAPS,
+            });
+        }
+        if num_threads == 0 {
+            return Err(SpartaError::InvalidConfig(
+                "at least one thread is required",
+            ));
+        }
+        LoadBalancer::from_submaps(num_users, num_threads, submaps, keys, Schedule::Parallel)
+    }
+
+    fn from_submaps(
+        num_users: usize,
+        num_threads: usize,
+        submaps: Vec<Box<dyn Submap<N>>>,
+        keys: UserKeys,
+        schedule: Schedule,
+    ) -> Result<Self> {
         let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(component_threads)
-            .build()
-            .unwrap();
+            .num_threads(num_threads)
+            .build()?;
 
         let mut user_store = Vec::new();
-        user_store.reserve(num_users as usize);
-        user_store.extend((0..num_users).map(|i| IndexRecord::new(i, RecordType::User)));
+        user_store.reserve(num_users);
+        user_store.extend((0..num_users).map(|i| IndexRecord::new(i as Uid, RecordType::User)));
 
-        let mut submaps = Vec::with_capacity(num_submaps as usize);
-        submaps.extend((0..num_submaps).map(|_| ObliviousMap::new(component_threads)));
-
-        LoadBalancer {
+        Ok(LoadBalancer {
             num_users,
-            num_submaps,
-            num_threads: component_threads,
+            num_submaps: submaps.len(),
+            num_threads,
+            thread_budget: None,
+            schedule,
+            submap_times: Vec::new(),
+            submap_size: 0,
             pool,
+            keys,
+            prf: Prf::generate(0),
+            round: 0,
+            retention: Retention::Forever,
+            quota: None,
+            lambda: DEFAULT_LAMBDA,
+            ack_timeout: None,
+            pending: PendingDeliveries::new(),
+            stored_sends: VecDeque::new(),
             user_store,
+            groups: GroupTable::new(DEFAULT_MAX_GROUP_SIZE),
             submaps,
-        }
+            failed_commit: None,
+        })
     }
 
-    fn pad_size(&self, num_requests: f64) -> usize {
+    /// Number of requests, real and dummy, every submap gets in a batch of
+    /// `num_requests` requests. Uniformly spread requests overflow it on some
+    /// submap with probability at most 2^-lambda.
+    pub fn pad_size(&self, num_requests: usize) -> usize {
+        let num_requests = num_requests as f64;
         let num_submaps = self.num_submaps as f64;
         let mu = num_requests / num_submaps;
-        let gamma = (num_submaps + 2_f64.powf(LAMBDA as f64)).ln();
+        // ln(num_submaps + 2^lambda), without overflowing for large lambda
+        let lambda = self.lambda as f64;
+        let gamma = lambda * LN_2 + (num_submaps / 2_f64.powf(lambda)).ln_1p();
         let rhs = (gamma / mu - 1_f64) / E;
-        num_requests
-            .min(mu * E.powf(fast::lambertw(rhs as f32) as f64 + 1_f64))
-            .ceil() as usize
+        num_requests.min(mu * E.powf(lambertw(rhs) + 1_f64)).ceil() as usize
+    }
+
+    /// Padding of a batch of `num_requests` requests under the current
+    /// security parameter.
+    pub fn padding(&self, num_requests: usize) -> Padding {
+        let submap_size = self.pad_size(num_requests);
+        Padding {
+            num_requests,
+            submap_size,
+            dummies: (submap_size * self.num_submaps).saturating_sub(num_requests),
+        }
     }
 
-    pub fn pad_for_submap(
+    fn pad_for_submap(
         &self,
-        mut requests: Vec<SubmapRecord>,
+        mut requests: Vec<SubmapRecord<N>>,
         submap_size: usize,
         is_send: bool,
-    ) -> Vec<SubmapRecord> {
+    ) -> Vec<SubmapRecord<N>> {
         requests.reserve(self.num_submaps * submap_size);
 
         for submap in 0..self.num_submaps {
             if is_send {
-                requests.extend(SubmapRecord::dummy_send(submap_size, submap as u8));
+                requests.extend(SubmapRecord::dummy_send(submap_size, submap as u32));
             } else {
-                requests.extend(SubmapRecord::dummy_fetch(submap_size, submap as u8));
+                requests.extend(SubmapRecord::dummy_fetch(submap_size, submap as u32));
             }
         }
         requests
     }
 
-    pub fn get_submap_requests(
+    /// Pads the requests for every submap to the same size and sorts them by
+    /// submap, returning them along with that size. Should some submap get more
+    /// requests than `pad_size` allows, the requests are padded again to their
+    /// full number, which no submap can exceed, so no request is ever dropped.
+    /// Only the overflow itself is revealed, not which submap overflowed.
+    fn get_submap_requests(
         &self,
-        requests: Vec<IndexRecord>,
+        requests: Vec<IndexRecord<N>>,
+        is_send: bool,
+    ) -> (Vec<SubmapRecord<N>>, usize) {
+        let num_requests = requests.len();
+        let submap_size = self.pad_size(num_requests);
+        let retry = (submap_size < num_requests).then(|| requests.clone());
+
+        let (padded, overflow) = self.pad_requests(requests, submap_size, is_send);
+        match retry {
+            Some(requests) if overflow => {
+                let (padded, _) = self.pad_requests(requests, num_requests, is_send);
+                (padded, num_requests)
+            }
+            _ => (padded, submap_size),
+        }
+    }
+
+    /// Pads every submap with `submap_size` dummies, keeps its first
+    /// `submap_size` requests and reports whether any real ones were cut.
+    fn pad_requests(
+        &self,
+        requests: Vec<IndexRecord<N>>,
         submap_size: usize,
         is_send: bool,
-    ) -> Vec<SubmapRecord> {
-        let requests: Vec<SubmapRecord> = requests.into_iter().map(|r| SubmapRecord(r.0)).collect();
+    ) -> (Vec<SubmapRecord<N>>, bool) {
+        let requests: Vec<SubmapRecord<N>> =
+            requests.into_iter().map(|r| SubmapRecord(r.0)).collect();
 
         let mut requests = self.pad_for_submap(requests, submap_size, is_send);
 
         requests = otils::sort(requests, &self.pool, self.num_threads); // sort by omap, then by dummy
 
-        let mut prev_map = self.num_submaps;
-        let mut remaining_marks = submap_size as i32;
+        let mut prev_map = u32::MAX;
+        let mut remaining_marks = submap_size as i64;
+        let mut map_size = 0;
+        let mut overflow = false;
         for request in requests.iter_mut() {
-            let submap = request.0.map as u32;
-            remaining_marks = i32::oselect(
-                submap != prev_map as u32,
-                submap_size as i32,
-                remaining_marks,
-            );
+            let submap = request.0.map;
+            remaining_marks = i64::oselect(submap != prev_map, submap_size as i64, remaining_marks);
             request.0.mark = u16::oselect(remaining_marks > 0, 1, 0);
-            remaining_marks += i32::oselect(remaining_marks > 0, -1, 0);
-            prev_map = submap as usize;
+            remaining_marks += i64::oselect(remaining_marks > 0, -1, 0);
+
+            // every submap holds its requests and exactly `submap_size` dummies
+            map_size = usize::oselect(submap != prev_map, 1, map_size + 1);
+            overflow |= (submap != u32::MAX) & (map_size > 2 * submap_size);
+            prev_map = submap;
         }
 
         otils::compact(
@@ -108,47 +399,693 @@ impl LoadBalancer {
             self.num_threads,
         );
         requests.truncate(self.num_submaps * submap_size);
-        requests
+        (requests, overflow)
+    }
+
+    /// Time each submap spent on its part of the last batch.
+    pub fn submap_times(&self) -> &[Duration] {
+        &self.submap_times
+    }
+
+    /// Number of requests every submap received in the last batch.
+    pub fn submap_size(&self) -> usize {
+        self.submap_size
+    }
+
+    /// Hands each submap the next `submap_size` requests and runs `f` on it,
+    /// all submaps at once or one after another depending on the schedule.
+    /// Returns the results by submap. A submap that panics fails the call but
+    /// does not stop the others; run inside `transaction` to undo their work.
+    fn run_submaps<T: Send>(
+        &mut self,
+        mut requests: Vec<Record<N>>,
+        submap_size: usize,
+        f: impl Fn(&mut dyn Submap<N>, Vec<Record<N>>) -> Result<T> + Sync,
+    ) -> Result<Vec<T>> {
+        let batches: Vec<Vec<Record<N>>> = (0..self.num_submaps)
+            .map(|_| requests.drain(0..submap_size).collect())
+            .collect();
+        let run = |i: usize, submap: &mut Box<dyn Submap<N>>, batch| {
+            let start = Instant::now();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(submap.as_mut(), batch)))
+                .unwrap_or_else(|payload| {
+                    Err(SpartaError::SubmapFailed {
+                        submap: i,
+                        reason: panic_reason(payload),
+                    })
+                });
+            (start.elapsed(), result)
+        };
+
+        let results: Vec<(Duration, Result<T>)> = match self.schedule {
+            Schedule::Parallel => thread::scope(|s| {
+                let run = &run;
+                let handles: Vec<_> = self
+                    .submaps
+                    .iter_mut()
+                    .zip(batches)
+                    .enumerate()
+                    .map(|(i, (submap, batch))| s.spawn(move || run(i, submap, batch)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect()
+            }),
+            Schedule::Sequential => self
+                .submaps
+                .iter_mut()
+                .zip(batches)
+                .enumerate()
+                .map(|(i, (submap, batch))| run(i, submap, batch))
+                .collect(),
+        };
+
+        self.submap_size = submap_size;
+        self.submap_times = results.iter().map(|(time, _)| *time).collect();
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Runs `f` as one transaction over the user store, the PRF, the pending
+    /// deliveries and every submap. If it fails, all of them are rolled back to
+    /// where they were before, so the batch can be retried; otherwise every
+    /// submap commits.
+    ///
+    /// Submaps commit one after another, so a commit that fails leaves the
+    /// submaps before it ahead of the rest. The load balancer then rolls back
+    /// what it can and fails every later batch with `CommitFailed`; it has to
+    /// be restored from a snapshot. Batches since that snapshot are lost, as
+    /// they are when the load balancer itself crashes.
+    ///
+    /// Rolling back needs a copy of the user store here and of each submap's
+    /// store, taken when the batch starts. The copies are linear in the
+    /// stores, below the cost of the oblivious sorts every batch runs over
+    /// them, but they count towards batch latencies.
+    fn transaction<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if let Some((submap, reason)) = &self.failed_commit {
+            return Err(SpartaError::CommitFailed {
+                submap: *submap,
+                reason: reason.clone(),
+            });
+        }
+
+        let user_store = self.user_store.clone();
+        let prf = self.prf.clone();
+        let num_submaps = self.num_submaps;
+        let pending = self.pending.clone();
+        let stored_sends = self.stored_sends.clone();
+
+        let result = f(self).and_then(|result| {
+            for (i, submap) in self.submaps.iter_mut().enumerate() {
+                if let Err(e) = submap.commit() {
+                    let reason = e.to_string();
+                    self.failed_commit = Some((i, reason.clone()));
+                    return Err(SpartaError::CommitFailed { submap: i, reason });
+                }
+            }
+            Ok(result)
+        });
+        if result.is_err() {
+            self.user_store = user_store;
+            self.prf = prf;
+            self.num_submaps = num_submaps;
+            self.pending = pending;
+            self.stored_sends = stored_sends;
+            for submap in self.submaps.iter_mut() {
+                // a submap that cannot roll back has already lost its
+                // uncommitted state along with its connection
+                let _ = submap.rollback();
+            }
+        }
+        result
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.prf.epoch()
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    /// Starts the next round; messages sent from now on are stamped with it.
+    pub fn next_round(&mut self) {
+        self.round += 1;
+    }
+
+    /// Evicts undelivered messages that have outlived the retention policy
+    /// from every submap.
+    pub fn collect_garbage(&mut self) -> Result<()> {
+        let Retention::Rounds(rounds) = self.retention else {
+            return Ok(());
+        };
+        let oldest_round = (self.round + 1).saturating_sub(rounds);
+
+        self.transaction(|l| {
+            thread::scope(|s| {
+                let handles: Vec<_> = l
+                    .submaps
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(i, submap)| {
+                        s.spawn(move || {
+                            panic::catch_unwind(AssertUnwindSafe(|| {
+                                submap.collect_garbage(oldest_round)
+                            }))
+                            .unwrap_or_else(|payload| {
+                                Err(SpartaError::SubmapFailed {
+                                    submap: i,
+                                    reason: panic_reason(payload),
+                                })
+                            })
+                        })
+                    })
+                    .collect();
+                let results: Vec<Result<()>> = handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect();
+                results.into_iter().collect::<Result<()>>()
+            })?;
+
+            while let Some((round, _)) = l.stored_sends.front() {
+                if *round >= oldest_round {
+                    break;
+                }
+                l.stored_sends.pop_front();
+            }
+            Ok(())
+        })
+    }
+
+    /// Replaces the PRF key and moves every stored message to the submap its
+    /// index maps to under the new key. The padding stored with the messages
+    /// is dropped rather than moved, see `rebalance`.
+    pub fn rotate_prf(&mut self) -> Result<()> {
+        self.transaction(|l| {
+            l.prf = l.prf.rotate();
+            l.rebalance(l.num_submaps)
+        })
+    }
+
+    /// Adds `submaps`, which must be empty, and moves every stored message to
+    /// the submap its index maps to among the larger set.
+    pub fn add_submaps(&mut self, submaps: Vec<Box<dyn Submap<N>>>) -> Result<()> {
+        let num_submaps = self.num_submaps + submaps.len();
+        if num_submaps > MAX_SUBMAPS {
+            return Err(SpartaError::TooManySubmaps {
+                num_submaps,
+                max: MAX_SUBMAPS,
+            });
+        }
+
+        self.submaps.extend(submaps);
+        let result = self.transaction(|l| l.rebalance(num_submaps));
+        if result.is_err() {
+            self.submaps.truncate(self.num_submaps);
+        }
+        result
+    }
+
+    /// Moves the messages of the last `count` submaps, along with all others,
+    /// to the submap their index maps to among the remaining ones. Returns the
+    /// removed submaps, now empty.
+    pub fn remove_submaps(&mut self, count: usize) -> Result<Vec<Box<dyn Submap<N>>>> {
+        if count >= self.num_submaps {
+            return Err(SpartaError::InvalidRequest(
+                "at least one submap must remain",
+            ));
+        }
+        let num_submaps = self.num_submaps - count;
+        self.transaction(|l| l.rebalance(num_submaps))?;
+        Ok(self.submaps.split_off(num_submaps))
+    }
+
+    /// Grows or shrinks to `num_submaps` submaps. If the load balancer split
+    /// its threads with the submaps it created, the threads are split again
+    /// over the new number of components, and every submap is replaced by one
+    /// with its new share; there must still be at least one thread each.
+    /// Otherwise new submaps are oblivious maps in this process with as many
+    /// threads as the load balancer.
+    pub fn resize(&mut self, num_submaps: usize) -> Result<()> {
+        if num_submaps == 0 {
+            return Err(SpartaError::InvalidConfig(
+                "at least one submap is required",
+            ));
+        }
+        if let Some(budget) = self.thread_budget {
+            if num_submaps != self.num_submaps {
+                return self.split_threads(num_submaps, budget);
+            }
+        }
+        match num_submaps.cmp(&self.num_submaps) {
+            cmp::Ordering::Greater => {
+                let submaps = (self.num_submaps..num_submaps)
+                    .map(|_| {
+                        let submap = ObliviousMap::new(self.num_threads)?;
+                        Ok(Box::new(submap) as Box<dyn Submap<N>>)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                self.add_submaps(submaps)
+            }
+            cmp::Ordering::Less => {
+                self.remove_submaps(self.num_submaps - num_submaps)?;
+                Ok(())
+            }
+            cmp::Ordering::Equal => Ok(()),
+        }
+    }
+
+    /// Moves every stored message into `num_submaps` new submaps, each with
+    /// an even share of `budget` threads, and gives the load balancer the same
+    /// share, as `with_schedule` would for `num_submaps` submaps.
+    fn split_threads(&mut self, num_submaps: usize, budget: usize) -> Result<()> {
+        if num_submaps > MAX_SUBMAPS {
+            return Err(SpartaError::TooManySubmaps {
+                num_submaps,
+                max: MAX_SUBMAPS,
+            });
+        }
+        let component_threads = budget / (num_submaps + 1);
+        if component_threads == 0 {
+            return Err(SpartaError::InvalidConfig(
+                "too few threads for the load balancer and its submaps",
+            ));
+        }
+        let mut submaps = (0..num_submaps)
+            .map(|_| {
+                let submap = ObliviousMap::new(component_threads)?;
+                Ok(Box::new(submap) as Box<dyn Submap<N>>)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(component_threads)
+            .build()?;
+
+        // the new submaps come first, so rebalancing drains the old ones into them
+        submaps.append(&mut self.submaps);
+        self.submaps = submaps;
+        let result = self.transaction(|l| l.rebalance(num_submaps));
+        match result {
+            Ok(()) => {
+                self.submaps.truncate(num_submaps);
+                self.pool = pool;
+                self.num_threads = component_threads;
+                Ok(())
+            }
+            Err(e) => {
+                self.submaps.drain(..num_submaps);
+                Err(e)
+            }
+        }
+    }
+
+    /// Drains every submap and refills the first `num_submaps` with the stored
+    /// messages, each sent to the submap its index maps to. Drained dummies are
+    /// dropped, and the messages are padded as a send batch of as many records
+    /// as were sent since the oldest live round, so every submap is refilled
+    /// to the same size whatever the number of messages, and repeated moves do
+    /// not grow the store. The move does not reveal which messages moved or
+    /// where to.
+    fn rebalance(&mut self, num_submaps: usize) -> Result<()> {
+        let mut stored: Vec<IndexRecord<N>> = Vec::new();
+        for submap in self.submaps.iter_mut() {
+            stored.extend(submap.drain()?.into_iter().map(IndexRecord));
+        }
+
+        self.num_submaps = num_submaps;
+        for record in stored.iter_mut() {
+            let is_send = record.0.is_send();
+            let idx = record.get_idx(&self.prf, record.0.last_send);
+            record.0.idx = u32::oselect(is_send, idx, record.0.idx);
+            // dummies go to no submap, so they never count as an overflow
+            record.0.map =
+                u32::oselect(is_send, record.0.idx % (self.num_submaps as u32), u32::MAX);
+            record.0.padding = !is_send;
+        }
+        otils::compact(
+            &mut stored[..],
+            |r| r.0.is_send(),
+            &self.pool,
+            self.num_threads,
+        );
+        let num_sends = self
+            .stored_sends
+            .iter()
+            .fold(0, |acc, (_, count)| acc + count);
+        stored.truncate(num_sends);
+
+        let (mut stored, submap_size) = self.get_submap_requests(stored, true);
+        for submap in self.submaps[..num_submaps].iter_mut() {
+            let batch = stored.drain(0..submap_size).map(|r| r.0).collect();
+            submap.batch_send(batch, self.round)?;
+        }
+        Ok(())
+    }
+
+    /// Seals the keys, padding and acknowledgement settings, user store
+    /// counters and submap contents to `path`. The caller keeps `counter` in
+    /// trusted monotonic storage and bumps it on every snapshot, so an older
+    /// snapshot cannot be replayed on restore.
+    pub fn snapshot(&self, path: &Path, key: &[u8; SEALING_KEY_SIZE], counter: u64) -> Result<()> {
+        if let Some((submap, reason)) = &self.failed_commit {
+            return Err(SpartaError::CommitFailed {
+                submap: *submap,
+                reason: reason.clone(),
+            });
+        }
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&(self.num_users as u64).to_le_bytes());
+        body.extend_from_slice(&(self.num_submaps as u64).to_le_bytes());
+        body.extend_from_slice(&(N as u64).to_le_bytes());
+        body.extend_from_slice(self.keys.master());
+        body.extend_from_slice(self.prf.key());
+        body.extend_from_slice(&self.prf.epoch().to_le_bytes());
+        body.extend_from_slice(&self.round.to_le_bytes());
+        body.extend_from_slice(&(self.lambda as u64).to_le_bytes());
+        body.push(self.ack_timeout.is_some() as u8);
+        body.extend_from_slice(&self.ack_timeout.unwrap_or(0).to_le_bytes());
+
+        body.extend_from_slice(&(self.user_store.len() as u64).to_le_bytes());
+        for record in self.user_store.iter() {
+            body.extend_from_slice(&record.0.uid.to_le_bytes());
+            body.push(record.0.padding as u8);
+            body.extend_from_slice(&record.0.last_fetch.to_le_bytes());
+            body.extend_from_slice(&record.0.last_send.to_le_bytes());
+        }
+
+        body.extend_from_slice(&(self.groups.max_group_size() as u64).to_le_bytes());
+        body.extend_from_slice(&(self.groups.rows().len() as u64).to_le_bytes());
+        for (gid, member) in self.groups.rows().iter() {
+            body.extend_from_slice(&gid.to_le_bytes());
+            body.push(member.is_some() as u8);
+            body.extend_from_slice(&member.unwrap_or(0).to_le_bytes());
+        }
+
+        for submap in self.submaps.iter() {
+            submap.encode(&mut body)?;
+        }
+        self.pending.encode(&mut body);
+
+        Ok(snapshot::seal(path, key, counter, &body)?)
+    }
+
+    /// Restores a snapshot taken with `snapshot` into local submaps.
+    pub fn restore(
+        path: &Path,
+        key: &[u8; SEALING_KEY_SIZE],
+        counter: u64,
+        num_threads: usize,
+    ) -> Result<Self> {
+        LoadBalancer::restore_into(path, key, counter, |num_submaps, keys| {
+            LoadBalancer::new(0, num_threads, num_submaps, keys)
+        })
     }
 
+    /// Like `restore`, but refills `submaps`, which must be empty and as many
+    /// as the snapshot holds, as `with_submaps` uses them.
+    pub fn restore_with_submaps(
+        path: &Path,
+        key: &[u8; SEALING_KEY_SIZE],
+        counter: u64,
+        num_threads: usize,
+        submaps: Vec<Box<dyn Submap<N>>>,
+    ) -> Result<Self> {
+        LoadBalancer::restore_into(path, key, counter, |num_submaps, keys| {
+            if submaps.len() != num_submaps {
+                return Err(SpartaError::InvalidConfig(
+                    "number of submaps does not match the snapshot",
+                ));
+            }
+            LoadBalancer::with_submaps(0, num_threads, submaps, keys)
+        })
+    }
+
+    fn restore_into(
+        path: &Path,
+        key: &[u8; SEALING_KEY_SIZE],
+        counter: u64,
+        open: impl FnOnce(usize, UserKeys) -> Result<Self>,
+    ) -> Result<Self> {
+        let body = snapshot::open(path, key, counter)?;
+        let mut decoder = Decoder::new(&body);
+
+        let num_users = decoder.u64()? as usize;
+        let num_submaps = decoder.u64()? as usize;
+        let message_size = decoder.u64()? as usize;
+        if message_size != N {
+            return Err(SpartaError::MessageSizeMismatch {
+                expected: N,
+                found: message_size,
+            });
+        }
+        let keys = UserKeys::new(decoder.bytes()?);
+
+        let mut l = open(num_submaps, keys)?;
+        l.num_users = num_users;
+        l.prf = Prf::new(decoder.bytes()?, decoder.u64()?);
+        l.round = decoder.u64()?;
+        l.lambda = decoder.u64()? as usize;
+        let has_ack_timeout = decoder.u8()? == 1;
+        let ack_timeout = decoder.u64()?;
+        l.ack_timeout = has_ack_timeout.then_some(ack_timeout);
+
+        let num_records = decoder.u64()?;
+        for _ in 0..num_records {
+            let mut record = IndexRecord::new(decoder.u128()?, RecordType::User);
+            record.0.padding = decoder.u8()? == 1;
+            record.0.last_fetch = decoder.u32()?;
+            record.0.last_send = decoder.u32()?;
+            l.user_store.push(record);
+        }
+
+        let max_group_size = decoder.u64()? as usize;
+        let num_rows = decoder.u64()?;
+        let rows = (0..num_rows)
+            .map(|_| {
+                let gid = decoder.u128()?;
+                let has_member = decoder.u8()? == 1;
+                let member = decoder.u128()?;
+                Ok((gid, has_member.then_some(member)))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        l.groups = GroupTable::from_rows(max_group_size, rows)?;
+
+        let mut num_stored = 0;
+        for submap in l.submaps.iter_mut() {
+            let num_records = decoder.u64()?;
+            let records = (0..num_records)
+                .map(|_| Record::decode(&mut decoder))
+                .collect::<io::Result<Vec<Record<N>>>>()?;
+            num_stored += records.len();
+            submap.batch_send(records, l.round)?;
+            submap.commit()?;
+        }
+        // every restored record may be a live message until this round expires
+        l.stored_sends.push_back((l.round, num_stored));
+        l.pending = PendingDeliveries::decode(&mut decoder)?;
+
+        Ok(l)
+    }
+
+    fn random_ids(count: usize) -> Vec<Uid> {
+        let mut bytes = vec![0; 16 * count];
+        OsRng.fill_bytes(&mut bytes);
+        bytes
+            .chunks(16)
+            .map(|b| Uid::from_le_bytes(b.try_into().unwrap()))
+            .collect()
+    }
+
+    /// Registers `count` new users under fresh random ids, which are returned.
+    /// The user store always grows by `slots` entries: the remaining slots are
+    /// filled with phantom users, padding entries no client can address, so the
+    /// growth does not reveal how many users joined. Ids that collide with a
+    /// registered user are drawn again.
+    ///
+    /// Registration is an operator operation: the protocol has no request for
+    /// it, and the new ids and their keys reach clients out of band.
+    pub fn batch_register(&mut self, count: usize, slots: usize) -> Result<Vec<Uid>> {
+        if count > slots {
+            return Err(SpartaError::InvalidRequest("more registrations than slots"));
+        }
+
+        loop {
+            let ids = LoadBalancer::<N>::random_ids(slots);
+            if self.register_ids(&ids, count) {
+                return Ok(ids[..count].to_vec());
+            }
+        }
+    }
+
+    /// Adds the first `count` of `ids` as users and the rest as phantoms,
+    /// unless a new user shares its id with another user. Returns whether the
+    /// ids were added. The check sorts the whole store, so it only reveals
+    /// whether some id collided.
+    fn register_ids(&mut self, ids: &[Uid], count: usize) -> bool {
+        let user_store = self.user_store.clone();
+        self.user_store.reserve(ids.len());
+        self.user_store
+            .extend(ids.iter().enumerate().map(|(i, &uid)| {
+                let mut record = IndexRecord::new(uid, RecordType::User);
+                record.0.padding = i >= count;
+                record
+            }));
+        self.user_store = otils::sort(
+            std::mem::take(&mut self.user_store),
+            &self.pool,
+            self.num_threads,
+        );
+
+        let is_collision = self.user_store.windows(2).fold(false, |acc, pair| {
+            acc | (!pair[0].0.padding & !pair[1].0.padding & (pair[0].0.uid == pair[1].0.uid))
+        });
+        if is_collision {
+            self.user_store = user_store;
+            return false;
+        }
+
+        self.num_users += ids.len();
+        true
+    }
+
+    /// Removes users from the user store. Each removed entry first becomes a
+    /// phantom, then the store shrinks by `slots` entries, dropping phantoms
+    /// only, so how many of the slots were real removals stays hidden. If too
+    /// few phantoms remain the store stops shrinking at the registered users.
+    /// Like registration, removal is not reachable through the protocol.
+    pub fn batch_unregister(&mut self, uids: Vec<Uid>, slots: usize) -> Result<()> {
+        if uids.len() > slots {
+            return Err(SpartaError::InvalidRequest("more removals than slots"));
+        }
+
+        let num_requests = slots;
+        self.user_store.reserve(num_requests);
+        self.user_store.extend(
+            uids.iter()
+                .map(|&uid| IndexRecord::new(uid, RecordType::Dummy)),
+        );
+        self.user_store
+            .extend((uids.len()..slots).map(|_| IndexRecord::maximum()));
+
+        self.user_store = otils::sort(
+            std::mem::take(&mut self.user_store),
+            &self.pool,
+            self.num_threads,
+        );
+
+        let mut user_store_iter = self.user_store.iter_mut().peekable();
+        while let Some(record) = user_store_iter.next() {
+            let is_removed = match user_store_iter.peek() {
+                Some(next_record) => {
+                    record.0.is_user_store()
+                        && !record.0.padding
+                        && !next_record.0.padding
+                        && next_record.0.uid == record.0.uid
+                }
+                None => false,
+            };
+            record.0.padding = u8::oselect(is_removed, 1, record.0.padding as u8) == 1;
+        }
+
+        otils::compact(
+            &mut self.user_store[..],
+            |r| r.0.is_user_store(),
+            &self.pool,
+            self.num_threads,
+        );
+        let num_entries = self.user_store.len() - num_requests;
+        self.user_store.truncate(num_entries);
+
+        otils::compact(
+            &mut self.user_store[..],
+            |r| !r.0.padding,
+            &self.pool,
+            self.num_threads,
+        );
+        let num_registered = self
+            .user_store
+            .iter()
+            .fold(0, |acc, r| acc + usize::oselect(r.0.padding, 0, 1));
+        let num_entries = cmp::max(num_entries.saturating_sub(slots), num_registered);
+        self.user_store.truncate(num_entries);
+        self.num_users = num_entries;
+        Ok(())
+    }
+
+    /// Assigns each send the next index of its recipient. A user's pending
+    /// count is the gap between its send and fetch counters; sends that would
+    /// push it past the quota become dummies and do not advance the counter,
+    /// as do sends to ids without a user store entry and sends past the last
+    /// index a counter can hold.
     fn propagate_send_indices(&mut self) {
+        let quota = self.quota.unwrap_or(u32::MAX);
         let mut idx: u32 = 0;
+        let mut last_fetch: u32 = 0;
+        let mut registered = false;
+        let mut prev = (true, Uid::MAX);
         let mut is_same_u: bool;
 
         let mut user_store_iter = self.user_store.iter_mut().peekable();
         while let Some(record) = user_store_iter.next() {
             let is_user_store = record.0.is_user_store();
+            let is_same_prev = (record.0.padding, record.0.uid) == prev;
+            prev = (record.0.padding, record.0.uid);
+            registered = is_user_store || (registered && is_same_prev);
+
+            // carry the fetch counter so the updated user store entry keeps it
+            last_fetch = u32::oselect(is_user_store, record.0.last_fetch, last_fetch);
+            record.0.last_fetch = last_fetch;
 
+            // pending count including this send, zero if the fetch counter is
+            // ahead, computed without branching on either counter
+            let next = idx as u64 + 1;
+            let pending = u64::oselect(
+                last_fetch as u64 > next,
+                0,
+                next.wrapping_sub(last_fetch as u64),
+            );
+            // a user whose send counter is exhausted cannot receive more
+            let is_full = next > u32::MAX as u64;
+            let is_dropped = !is_user_store & (!registered | is_full | (pending > quota as u64));
             idx = u32::oselect(
                 is_user_store,
                 cmp::max(record.0.last_fetch, record.0.last_send),
-                idx + 1,
+                u32::oselect(is_dropped, idx, next as u32),
             );
 
-            record.0.idx = u32::oselect(is_user_store, 0, record.get_idx(idx));
-            record.0.map = (record.0.idx % (self.num_submaps as u32)) as u8;
+            record.0.idx = u32::oselect(
+                is_user_store,
+                0,
+                u32::oselect(is_dropped, u32::MAX, record.get_idx(&self.prf, idx)),
+            );
+            record.0.map = record.0.idx % (self.num_submaps as u32);
             record.0.last_send = idx;
+            record.0.rec_type = RecordType::from_u8(u8::oselect(
+                is_dropped,
+                RecordType::Dummy as u8,
+                record.0.rec_type.clone() as u8,
+            ))
+            .unwrap();
 
             if let Some(next_record) = user_store_iter.peek() {
-                is_same_u = record.0.uid == next_record.0.uid;
+                is_same_u = (next_record.0.padding, next_record.0.uid) == prev;
             } else {
                 is_same_u = false;
             }
-            record.0.mark = u16::oselect(is_same_u, 0, 1);
+            record.0.mark = u16::oselect(is_same_u || !registered, 0, 1);
         }
     }
 
-    pub fn get_send_indices(&mut self, sends: Vec<IndexRecord>) -> Vec<IndexRecord> {
-        let num_requests = sends.len();
-        self.user_store.reserve(num_requests);
-        self.user_store.extend(sends);
-
-        self.user_store = otils::sort(
-            std::mem::take(&mut self.user_store),
-            &self.pool,
-            self.num_threads,
-        );
-        self.propagate_send_indices();
+    fn split_requests(&mut self, num_requests: usize) -> Vec<IndexRecord<N>> {
+        // the last record of each user holds its updated counters but may itself
+        // be a request, so the new user store is built from copies
+        let mut user_store: Vec<IndexRecord<N>> = self
+            .user_store
+            .iter()
+            .map(|r| r.user_store_entry())
+            .collect();
 
         otils::compact(
             &mut self.user_store[..],
@@ -159,84 +1096,207 @@ impl LoadBalancer {
         let requests = self.user_store.drain(0..num_requests).collect();
 
         otils::compact(
-            &mut self.user_store[..],
+            &mut user_store[..],
             |r| r.is_updated_user_store(),
             &self.pool,
             self.num_threads,
         );
-
-        self.user_store.truncate(self.num_users as usize);
-        self.user_store.iter_mut().for_each(|r| {
-            r.set_user_store();
-        });
+        user_store.truncate(self.num_users);
+        self.user_store = user_store;
 
         requests
     }
 
-    pub fn batch_send(&mut self, sends: Vec<Record>) {
-        let sends = sends.into_iter().map(|r| IndexRecord(r)).collect();
+    fn get_send_indices(&mut self, sends: Vec<IndexRecord<N>>) -> Vec<IndexRecord<N>> {
+        let num_requests = sends.len();
+        self.user_store.reserve(num_requests);
+        self.user_store.extend(sends);
+
+        self.user_store = otils::sort(
+            std::mem::take(&mut self.user_store),
+            &self.pool,
+            self.num_threads,
+        );
+        self.propagate_send_indices();
+        self.split_requests(num_requests)
+    }
+
+    /// Stores sends tagged with their position in the batch, with
+    /// `copies` records per position. Returns, by position, whether all copies
+    /// made it past the recipients' quotas.
+    fn store_sends(&mut self, sends: Vec<IndexRecord<N>>, copies: usize) -> Result<Vec<bool>> {
         let requests = self.get_send_indices(sends);
-        let submap_size = self.pad_size(requests.len() as f64);
-        let mut requests: Vec<Record> = self
-            .get_submap_requests(requests, submap_size, true)
+        match self.stored_sends.back_mut() {
+            Some((last, count)) if *last == self.round => *count += requests.len(),
+            _ => self.stored_sends.push_back((self.round, requests.len())),
+        }
+
+        let statuses = requests
+            .iter()
+            .map(|r| SendStatus {
+                position: r.0.position,
+                stored: r.0.is_send() || r.0.padding,
+            })
+            .collect();
+        let statuses = otils::sort(statuses, &self.pool, self.num_threads);
+        let stored = statuses
+            .chunks(copies)
+            .map(|chunk| chunk.iter().all(|status| status.stored))
+            .collect();
+
+        let round = self.round;
+        let (requests, submap_size) = self.get_submap_requests(requests, true);
+        let requests: Vec<Record<N>> = requests
             .into_iter()
-            .map(|r| r.0)
+            .map(|r| {
+                let mut record = r.0;
+                record.round = round;
+                // dropped sends never reach a fetch of their recipient
+                record.padding = !record.is_send();
+                record
+            })
             .collect();
 
-        let mut remaining_submaps = &mut self.submaps[..];
+        self.run_submaps(requests, submap_size, |submap, batch| {
+            submap.batch_send(batch, round)
+        })?;
 
-        self.pool.scope(|s| {
-            for _ in 0..self.num_submaps {
-                let (submap, rest_submaps) = remaining_submaps.split_at_mut(1);
-                remaining_submaps = rest_submaps;
+        Ok(stored)
+    }
 
-                let batch = requests.drain(0..submap_size).collect();
-                s.spawn(|_| submap[0].batch_send(batch));
-            }
+    fn open_sends(&self, sends: &[Envelope]) -> (Vec<Record<N>>, Vec<bool>) {
+        let mut accepted = Vec::with_capacity(sends.len());
+        let records = sends
+            .iter()
+            .enumerate()
+            .map(|(position, envelope)| {
+                let record = envelope.open_send(&self.keys.key(envelope.uid));
+                accepted.push(record.is_some());
+
+                let mut record = record.unwrap_or_else(|| IndexRecord::maximum().0);
+                record.position = position as u64;
+                record
+            })
+            .collect();
+        (records, accepted)
+    }
 
-            // let (submap, rest_submaps) = remaining_submaps.split_at_mut(1);
-            // remaining_submaps = rest_submaps;
+    /// Opens a fetch under the key of the user it fetches for, returning its
+    /// stamp and volume, or `None` if it does not authenticate.
+    pub fn open_fetch(&self, envelope: &Envelope) -> Option<(u64, u64)> {
+        envelope.open_fetch(&self.keys.key(envelope.uid))
+    }
 
-            // let batch = requests.drain(0..submap_size).collect();
-            // submap[0].batch_send(batch);
-        });
+    /// Opens an acknowledgement under the key of the user it acknowledges for,
+    /// returning the stamp of the fetch it names, or `None` if it does not
+    /// authenticate.
+    pub fn open_ack(&self, envelope: &Envelope) -> Option<u64> {
+        envelope.open_ack(&self.keys.key(envelope.uid))
+    }
+
+    /// Opens each envelope under its sender's key and stores the messages that
+    /// authenticate. Envelopes that fail to open still occupy a slot as dummy
+    /// sends, so the batch size does not depend on them. Returns, in request
+    /// order, whether each send was accepted; a send is refused if it does not
+    /// open or its recipient is over quota.
+    pub fn batch_send(&mut self, sends: Vec<Envelope>) -> Result<Vec<bool>> {
+        let (sends, accepted) = self.open_sends(&sends);
+        let sends = sends.into_iter().map(IndexRecord).collect();
+        let stored = self.transaction(|l| l.store_sends(sends, 1))?;
+        Ok(accepted
+            .into_iter()
+            .zip(stored)
+            .map(|(a, s)| a && s)
+            .collect())
     }
 
-    fn update_with_fetches(&mut self, fetches: Vec<IndexRecord>, num_fetches: usize) {
+    /// Like `batch_send`, but each envelope is addressed to a group id and is
+    /// delivered to every member of the group. Every send fans out to
+    /// `max_group_size` records regardless of the group's actual size. A group
+    /// send is refused if any member is over quota, though members with room
+    /// still receive it.
+    pub fn batch_group_send(&mut self, sends: Vec<Envelope>) -> Result<Vec<bool>> {
+        let copies = self.groups.max_group_size();
+        if copies == 0 {
+            return Err(SpartaError::InvalidConfig(
+                "group sends need a max group size of at least 1",
+            ));
+        }
+        if sends.len().checked_mul(copies).is_none() {
+            return Err(SpartaError::InvalidRequest("too many group sends"));
+        }
+
+        let (sends, accepted) = self.open_sends(&sends);
+        let sends = self.groups.expand(sends, &self.pool, self.num_threads);
+        let stored = self.transaction(|l| l.store_sends(sends, copies))?;
+        Ok(accepted
+            .into_iter()
+            .zip(stored)
+            .map(|(a, s)| a && s)
+            .collect())
+    }
+
+    fn update_with_fetches(&mut self, fetches: Vec<IndexRecord<N>>, num_fetches: usize) {
         self.user_store.reserve(num_fetches);
         for fetch in fetches.into_iter() {
             self.user_store.extend(fetch.dummy_fetches());
         }
     }
 
+    /// Assigns each fetch the next index of its user. Fetches for ids without
+    /// a user store entry get an index no message is stored under.
     fn propagate_fetch_indices(&mut self) {
         let mut idx: u32 = 0;
+        let mut last_send: u32 = 0;
+        let mut registered = false;
+        let mut prev = (true, Uid::MAX);
         let mut is_same_u: bool;
 
         let mut user_store_iter = self.user_store.iter_mut().peekable();
         while let Some(record) = user_store_iter.next() {
             let is_user_store = record.0.is_user_store();
+            let is_same_prev = (record.0.padding, record.0.uid) == prev;
+            prev = (record.0.padding, record.0.uid);
+            registered = is_user_store || (registered && is_same_prev);
 
-            idx = u32::oselect(is_user_store, record.0.last_fetch, idx + 1);
+            // fetches past the last index a counter can hold get dummies
+            let next = idx as u64 + 1;
+            let is_full = next > u32::MAX as u64;
+            idx = u32::oselect(
+                is_user_store,
+                record.0.last_fetch,
+                u32::oselect(is_full, idx, next as u32),
+            );
 
-            record.0.idx = u32::oselect(is_user_store, 0, record.get_idx(idx));
-            record.0.map = (record.0.idx % (self.num_submaps as u32)) as u8;
+            record.0.idx = u32::oselect(
+                is_user_store,
+                0,
+                u32::oselect(
+                    registered & !is_full,
+                    record.get_idx(&self.prf, idx),
+                    u32::MAX,
+                ),
+            );
+            record.0.map = record.0.idx % (self.num_submaps as u32);
             record.0.last_fetch = idx;
 
+            last_send = u32::oselect(is_user_store, record.0.last_send, last_send);
+            record.0.last_send = last_send;
+
             if let Some(next_record) = user_store_iter.peek() {
-                is_same_u = record.0.uid == next_record.0.uid;
+                is_same_u = (next_record.0.padding, next_record.0.uid) == prev;
             } else {
                 is_same_u = false;
             }
-            record.0.mark = u16::oselect(is_same_u, 0, 1);
+            record.0.mark = u16::oselect(is_same_u || !registered, 0, 1);
         }
     }
 
-    pub fn get_fetch_indices(
+    fn get_fetch_indices(
         &mut self,
-        fetches: Vec<IndexRecord>,
+        fetches: Vec<IndexRecord<N>>,
         num_requests: usize,
-    ) -> Vec<IndexRecord> {
+    ) -> Vec<IndexRecord<N>> {
         self.update_with_fetches(fetches, num_requests);
 
         self.user_store = otils::sort(
@@ -245,114 +1305,768 @@ impl LoadBalancer {
             self.num_threads,
         );
         self.propagate_fetch_indices();
+        self.split_requests(num_requests)
+    }
 
-        otils::compact(
-            &mut self.user_store[..],
-            |r| r.is_request(),
-            &self.pool,
-            self.num_threads,
+    /// Answers fetches in the clear with exactly `volume` records per fetch,
+    /// grouped by recipient. Padding fetches only fill the batch and get no
+    /// records.
+    fn fetch_records(&mut self, fetches: Vec<Record<N>>) -> Result<Vec<Record<N>>> {
+        validate_fetches(&fetches)?;
+
+        let num_requests = fetches
+            .iter()
+            .fold(0, |acc, fetch| acc + fetch.data as usize);
+        let num_delivered = fetches.iter().fold(0, |acc, fetch| {
+            acc + usize::oselect(fetch.padding, 0, fetch.data as usize)
+        });
+        let fetches = fetches.into_iter().map(IndexRecord).collect();
+
+        let requests = self.get_fetch_indices(fetches, num_requests);
+        let (requests, submap_size) = self.get_submap_requests(requests, false);
+        let requests: Vec<Record<N>> = requests.into_iter().map(|r| r.0).collect();
+
+        let responses: Vec<IndexRecord<N>> = self
+            .run_submaps(requests, submap_size, |submap, batch| {
+                submap.batch_fetch(batch)
+            })?
+            .into_iter()
+            .flatten()
+            .map(IndexRecord)
+            .collect();
+
+        // groups deliveries by recipient, padding responses sort last
+        let mut responses = otils::sort(responses, &self.pool, self.num_threads);
+        let delivered: Vec<Record<N>> = responses.drain(0..num_delivered).map(|r| r.0).collect();
+        if self.ack_timeout.is_some() {
+            self.pending.hold(&delivered, self.round);
+        }
+        Ok(delivered)
+    }
+
+    /// Acknowledges the deliveries of the fetches named in `acks`, each a user
+    /// and the round of its fetch, so that none of them is delivered again.
+    /// Deliveries of the user's other fetches stay held, so an acknowledgement
+    /// does not cover a delivery that was lost in transit. The batch is padded
+    /// to `slots` and leaves the number of held deliveries unchanged, so it
+    /// does not reveal whose deliveries were held.
+    pub fn batch_ack(&mut self, acks: Vec<(Uid, u64)>, slots: usize) -> Result<()> {
+        if acks.len() > slots {
+            return Err(SpartaError::InvalidRequest(
+                "more acknowledgements than slots",
+            ));
+        }
+        self.pending.ack(&acks, slots, &self.pool, self.num_threads);
+        Ok(())
+    }
+
+    /// Sends every delivery that has waited `ack_timeout` rounds without being
+    /// acknowledged to its recipient again, to be fetched like a new message.
+    /// Redeliveries were admitted once already, so the quota does not apply to
+    /// them. The batch holds one send per expired delivery, acknowledged ones
+    /// as padding, so its size only depends on earlier fetch batches.
+    pub fn redeliver(&mut self) -> Result<()> {
+        let Some(ack_timeout) = self.ack_timeout else {
+            return Ok(());
+        };
+        let oldest_round = (self.round + 1).saturating_sub(ack_timeout);
+
+        self.transaction(|l| {
+            let expired = l.pending.expire(oldest_round, &l.pool, l.num_threads);
+            if expired.is_empty() {
+                return Ok(());
+            }
+            let sends = expired
+                .into_iter()
+                .enumerate()
+                .map(|(position, mut record)| {
+                    record.position = position as u64;
+                    IndexRecord(record)
+                })
+                .collect();
+
+            let quota = l.quota.take();
+            let result = l.store_sends(sends, 1);
+            l.quota = quota;
+            result.map(|_| ())
+        })
+    }
+
+    /// Number of deliveries held for acknowledgement.
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Delivers exactly `volume` envelopes per fetch, each sealed under the
+    /// recipient's key. Deliveries are grouped by recipient. Padding fetches
+    /// only fill the batch and get no deliveries.
+    pub fn batch_fetch(&mut self, fetches: Vec<Record<N>>) -> Result<Vec<Envelope>> {
+        Ok(self
+            .transaction(|l| l.fetch_records(fetches))?
+            .into_iter()
+            .map(|r| Envelope::seal_delivery(&self.keys.key(r.uid), &r))
+            .collect())
+    }
+}
+
+impl<const N: usize> MessageStore<N> for LoadBalancer<N> {
+    /// Stores plaintext sends, skipping the envelopes clients seal them in.
+    fn batch_send(&mut self, sends: Vec<Record<N>>) -> Result<Vec<bool>> {
+        validate_sends(&sends)?;
+
+        let sends = sends
+            .into_iter()
+            .enumerate()
+            .map(|(position, mut record)| {
+                record.position = position as u64;
+                IndexRecord(record)
+            })
+            .collect();
+        self.transaction(|l| l.store_sends(sends, 1))
+    }
+
+    fn batch_fetch(&mut self, fetches: Vec<Record<N>>) -> Result<Vec<Record<N>>> {
+        self.transaction(|l| l.fetch_records(fetches))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc,
+    };
+
+    #[test]
+    fn test_user_store_counters() {
+        let keys = UserKeys::generate();
+        let mut l: LoadBalancer<8> = LoadBalancer::new(4, 6, 2, keys.clone()).unwrap();
+
+        let send = |x: u8| Envelope::seal_send(&keys.key(0), 0, 1, x as u64, &[x; 8]);
+        let fetch = |l: &mut LoadBalancer<8>| -> Vec<u8> {
+            l.batch_fetch(vec![Record::fetch(1, 1)])
+                .unwrap()
+                .iter()
+                .map(|envelope| envelope.open_delivery::<8>(&keys.key(1)).unwrap())
+                .filter(|record| record.is_send())
+                .map(|record| record.message[0])
+                .collect()
+        };
+
+        // each batch must leave the counters of the other kind untouched
+        assert_eq!(l.batch_send(vec![send(1)]).unwrap(), vec![true]);
+        assert_eq!(fetch(&mut l), vec![1]);
+        assert_eq!(l.batch_send(vec![send(2)]).unwrap(), vec![true]);
+        assert_eq!(fetch(&mut l), vec![2]);
+
+        let user = l.user_store.iter().find(|r| r.0.uid == 1).unwrap();
+        assert_eq!((user.0.last_send, user.0.last_fetch), (2, 2));
+    }
+
+    #[test]
+    fn test_padding_fetch() {
+        let keys = UserKeys::generate();
+        let mut l: LoadBalancer<8> = LoadBalancer::new(4, 6, 2, keys.clone()).unwrap();
+
+        let send = Envelope::seal_send(&keys.key(0), 0, 1, 1, &[3; 8]);
+        assert_eq!(
+            l.batch_send(vec![send, Envelope::dummy()]).unwrap(),
+            vec![true, false]
         );
-        let deliver = self.user_store.drain(0..num_requests).collect();
+        assert!(l
+            .batch_fetch(vec![Record::padding(RecordType::Fetch, 4, 0)])
+            .unwrap()
+            .is_empty());
 
-        otils::compact(
-            &mut self.user_store[..],
-            |r| r.is_updated_user_store(),
-            &self.pool,
-            self.num_threads,
+        let delivered = l.batch_fetch(vec![Record::fetch(1, 1)]).unwrap();
+        let record: Record<8> = delivered[0].open_delivery(&keys.key(1)).unwrap();
+        assert!(record.is_send() && record.message == [3; 8]);
+    }
+
+    #[test]
+    fn test_lambertw() {
+        let branch = -1_f64 / E;
+        for x in [
+            branch,
+            branch + 1e-12,
+            branch + 1e-6,
+            -0.3,
+            -0.01,
+            0.5,
+            1e3,
+            1e9,
+        ] {
+            let w = lambertw(x);
+            let wew = w * E.powf(w);
+            assert!(wew >= x && wew - x <= x.abs() * 1e-9, "W({})", x);
+        }
+    }
+
+    #[test]
+    fn test_pad_size() {
+        let mut l: LoadBalancer<4> = LoadBalancer::new(4, 6, 2, UserKeys::generate()).unwrap();
+        let default_size = l.pad_size(1 << 16);
+        assert!(default_size > 1 << 15 && default_size < 1 << 16);
+
+        l.lambda = 32;
+        assert!(l.pad_size(1 << 16) < default_size);
+        l.lambda = 100000;
+        assert_eq!(l.pad_size(1 << 16), 1 << 16);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let num_requests = 1024;
+        let l: LoadBalancer<4> =
+            LoadBalancer::with_schedule(4, 1, 4, UserKeys::generate(), Schedule::Sequential)
+                .unwrap();
+        assert!(l.pad_size(num_requests) < num_requests);
+
+        // every request maps to the same submap, far past the padded size
+        let requests: Vec<IndexRecord<4>> = (0..num_requests as Uid)
+            .map(|uid| {
+                let mut record = IndexRecord::new(uid, RecordType::Fetch);
+                record.0.map = 2;
+                record.0.idx = uid as u32;
+                record
+            })
+            .collect();
+        let (padded, submap_size) = l.get_submap_requests(requests, false);
+        assert_eq!(submap_size, num_requests);
+        let kept: Vec<&SubmapRecord<4>> = padded.iter().filter(|r| !r.0.padding).collect();
+        assert_eq!(kept.len(), num_requests);
+        assert!(kept.iter().all(|r| r.0.map == 2));
+    }
+
+    #[test]
+    fn test_many_submaps() {
+        let num_users = 64;
+        let mut l: LoadBalancer<4> = LoadBalancer::with_schedule(
+            num_users,
+            1,
+            300,
+            UserKeys::generate(),
+            Schedule::Sequential,
+        )
+        .unwrap();
+
+        let sends = (0..num_users as Uid)
+            .map(|uid| Record::send(uid, [uid as u8; 4]))
+            .collect();
+        assert_eq!(
+            MessageStore::batch_send(&mut l, sends).unwrap(),
+            vec![true; num_users]
         );
+        assert_eq!(l.submap_times().len(), 300);
 
-        self.user_store.truncate(self.num_users as usize);
-        self.user_store.iter_mut().for_each(|r| {
-            r.set_user_store();
-        });
+        let fetches = (0..num_users as Uid)
+            .map(|uid| Record::fetch(uid, 1))
+            .collect();
+        let delivered = MessageStore::batch_fetch(&mut l, fetches).unwrap();
+        let messages: Vec<(Uid, u8)> = delivered
+            .iter()
+            .filter(|r| r.is_send())
+            .map(|r| (r.uid, r.message[0]))
+            .collect();
+        assert_eq!(
+            messages,
+            (0..num_users as Uid)
+                .map(|uid| (uid, uid as u8))
+                .collect::<Vec<_>>()
+        );
+    }
 
-        deliver
+    // submap whose fetches and garbage collection passes panic while `fail` is
+    // set, and whose commits fail if `fail_commit` is
+    struct FlakySubmap {
+        omap: ObliviousMap<4>,
+        fail: Arc<AtomicBool>,
+        fail_commit: bool,
     }
 
-    pub fn batch_fetch(&mut self, fetches: Vec<Record>) -> Vec<Record> {
-        let num_requests = fetches
+    fn flaky_submaps(fail: &Arc<AtomicBool>, fail_commit: &[bool]) -> Vec<Box<dyn Submap<4>>> {
+        fail_commit
             .iter()
-            .fold(0, |acc, fetch| acc + fetch.data as usize);
-        let fetches = fetches.into_iter().map(|r| IndexRecord(r)).collect();
+            .map(|&fail_commit| {
+                Box::new(FlakySubmap {
+                    omap: ObliviousMap::new(1).unwrap(),
+                    fail: Arc::clone(fail),
+                    fail_commit,
+                }) as Box<dyn Submap<4>>
+            })
+            .collect()
+    }
 
-        // let start = std::time::SystemTime::now()
-        //     .duration_since(UNIX_EPOCH)
-        //     .unwrap()
-        //     .as_nanos();
-        let requests = self.get_fetch_indices(fetches, num_requests);
-        // let end = std::time::SystemTime::now()
-        //     .duration_since(UNIX_EPOCH)
-        //     .unwrap()
-        //     .as_nanos();
-        // println!("fetch idx {}: {}", requests.len(), end - start);
-
-        let submap_size = self.pad_size(requests.len() as f64);
-
-        // let start = std::time::SystemTime::now()
-        //     .duration_since(UNIX_EPOCH)
-        //     .unwrap()
-        //     .as_nanos();
-        let mut requests: Vec<Record> = self
-            .get_submap_requests(requests, submap_size, false)
-            .into_iter()
-            .map(|r| r.0)
-            .collect();
-        // let end = std::time::SystemTime::now()
-        //     .duration_since(UNIX_EPOCH)
-        //     .unwrap()
-        //     .as_nanos();
-        // println!("submap requests {}: {}", requests.len(), end - start);
-
-        let mut remaining_submaps = &mut self.submaps[..];
-        let responses: Arc<Mutex<Vec<IndexRecord>>> = Arc::new(Mutex::new(Vec::with_capacity(
-            submap_size * self.num_submaps,
-        )));
-
-        // let start = std::time::SystemTime::now()
-        //     .duration_since(UNIX_EPOCH)
-        //     .unwrap()
-        //     .as_nanos();
-        self.pool.scope(|s| {
-            for _ in 0..self.num_submaps {
-                let (submap, rest_submaps) = remaining_submaps.split_at_mut(1);
-                remaining_submaps = rest_submaps;
-                let batch = requests.drain(0..submap_size).collect();
-
-                s.spawn(|_| {
-                    let responses = Arc::clone(&responses);
-                    let response = submap[0].batch_fetch(batch);
-                    let mut responses = responses.lock().unwrap();
-                    responses.extend(response);
-                });
+    impl Submap<4> for FlakySubmap {
+        fn batch_send(&mut self, requests: Vec<Record<4>>, round: u64) -> Result<()> {
+            Submap::batch_send(&mut self.omap, requests, round)
+        }
+
+        fn batch_fetch(&mut self, requests: Vec<Record<4>>) -> Result<Vec<Record<4>>> {
+            assert!(!self.fail.load(AtomicOrdering::SeqCst), "submap crashed");
+            Submap::batch_fetch(&mut self.omap, requests)
+        }
+
+        fn collect_garbage(&mut self, oldest_round: u64) -> Result<()> {
+            assert!(!self.fail.load(AtomicOrdering::SeqCst), "submap crashed");
+            Submap::collect_garbage(&mut self.omap, oldest_round)
+        }
+
+        fn drain(&mut self) -> Result<Vec<Record<4>>> {
+            Submap::drain(&mut self.omap)
+        }
+
+        fn encode(&self, bytes: &mut Vec<u8>) -> Result<()> {
+            Submap::encode(&self.omap, bytes)
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            if self.fail_commit {
+                return Err(io::Error::from(io::ErrorKind::ConnectionReset).into());
             }
-        });
+            Submap::commit(&mut self.omap)
+        }
 
-        let mutex = Arc::into_inner(responses).unwrap();
-        let mut responses: Vec<IndexRecord> = mutex.into_inner().unwrap();
-        // let end = std::time::SystemTime::now()
-        //     .duration_since(UNIX_EPOCH)
-        //     .unwrap()
-        //     .as_nanos();
-        // println!("submap response {}: {}", responses.len(), end - start);
-
-        // this only really needs to be a shuffle
-        // let start = std::time::SystemTime::now()
-        //     .duration_since(UNIX_EPOCH)
-        //     .unwrap()
-        //     .as_nanos();
-        responses = otils::sort(responses, &self.pool, self.num_threads);
-        otils::compact(
-            &mut responses,
-            |r| r.0.is_send(),
-            &self.pool,
-            self.num_threads,
+        fn rollback(&mut self) -> Result<()> {
+            Submap::rollback(&mut self.omap)
+        }
+    }
+
+    #[test]
+    fn test_rollback() {
+        let num_users = 8;
+        let fail = Arc::new(AtomicBool::new(false));
+        let submaps = flaky_submaps(&fail, &[false, false]);
+        let mut l =
+            LoadBalancer::with_submaps(num_users, 2, submaps, UserKeys::generate()).unwrap();
+
+        let sends = (0..num_users as Uid)
+            .map(|uid| Record::send(uid, [uid as u8; 4]))
+            .collect();
+        MessageStore::batch_send(&mut l, sends).unwrap();
+
+        let fetches: Vec<Record<4>> = (0..num_users as Uid)
+            .map(|uid| Record::fetch(uid, 1))
+            .collect();
+        fail.store(true, AtomicOrdering::SeqCst);
+        assert!(matches!(
+            MessageStore::batch_fetch(&mut l, fetches.clone()),
+            Err(SpartaError::SubmapFailed { .. })
+        ));
+
+        // the failed batch left no trace, so the retry still finds every message
+        fail.store(false, AtomicOrdering::SeqCst);
+        let delivered = MessageStore::batch_fetch(&mut l, fetches).unwrap();
+        assert_eq!(delivered.iter().filter(|r| r.is_send()).count(), num_users);
+    }
+
+    #[test]
+    fn test_garbage_collection_panic() {
+        let fail = Arc::new(AtomicBool::new(false));
+        let submaps = flaky_submaps(&fail, &[false, false]);
+        let mut l = LoadBalancer::with_submaps(4, 2, submaps, UserKeys::generate()).unwrap();
+        l.retention = Retention::Rounds(1);
+
+        fail.store(true, AtomicOrdering::SeqCst);
+        assert!(matches!(
+            l.collect_garbage(),
+            Err(SpartaError::SubmapFailed { .. })
+        ));
+        fail.store(false, AtomicOrdering::SeqCst);
+        l.collect_garbage().unwrap();
+    }
+
+    #[test]
+    fn test_failed_commit() {
+        let fail = Arc::new(AtomicBool::new(false));
+        let submaps = flaky_submaps(&fail, &[false, true]);
+        let mut l = LoadBalancer::with_submaps(4, 2, submaps, UserKeys::generate()).unwrap();
+
+        let sends = vec![Record::send(1, [1; 4])];
+        assert!(matches!(
+            MessageStore::batch_send(&mut l, sends.clone()),
+            Err(SpartaError::CommitFailed { submap: 1, .. })
+        ));
+        // the submaps disagree now, so nothing runs until a restore
+        assert!(matches!(
+            MessageStore::batch_send(&mut l, sends),
+            Err(SpartaError::CommitFailed { submap: 1, .. })
+        ));
+        let path = std::env::temp_dir().join(format!("sparta-commit-test-{}", std::process::id()));
+        assert!(matches!(
+            l.snapshot(&path, &[3; SEALING_KEY_SIZE], 1),
+            Err(SpartaError::CommitFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resize() {
+        let num_users = 16;
+        let mut l: LoadBalancer<4> =
+            LoadBalancer::new(num_users, 6, 2, UserKeys::generate()).unwrap();
+        let sends = (0..num_users as Uid)
+            .map(|uid| Record::send(uid, [uid as u8; 4]))
+            .collect();
+        MessageStore::batch_send(&mut l, sends).unwrap();
+
+        // six threads leave none for a seventh component
+        assert!(matches!(l.resize(6), Err(SpartaError::InvalidConfig(_))));
+        l.resize(5).unwrap();
+        assert!(matches!(
+            l.remove_submaps(5),
+            Err(SpartaError::InvalidRequest(_))
+        ));
+        assert_eq!(l.remove_submaps(2).unwrap().len(), 2);
+        l.rotate_prf().unwrap();
+
+        let fetches = (0..num_users as Uid)
+            .map(|uid| Record::fetch(uid, 1))
+            .collect();
+        let delivered = MessageStore::batch_fetch(&mut l, fetches).unwrap();
+        assert_eq!(l.submap_times().len(), 3);
+        assert!(delivered
+            .iter()
+            .all(|r| r.is_send() && r.message[0] == r.uid as u8));
+    }
+
+    #[test]
+    fn test_restore_after_resize() {
+        let path = std::env::temp_dir().join(format!("sparta-resize-test-{}", std::process::id()));
+        let key = [4; SEALING_KEY_SIZE];
+        let mut l: LoadBalancer<4> = LoadBalancer::new(4, 4, 1, UserKeys::generate()).unwrap();
+        MessageStore::batch_send(&mut l, vec![Record::send(1, [9; 4])]).unwrap();
+        l.resize(3).unwrap();
+        l.snapshot(&path, &key, 1).unwrap();
+
+        let mut restored = LoadBalancer::<4>::restore(&path, &key, 1, 4).unwrap();
+        let delivered =
+            MessageStore::batch_fetch(&mut restored, vec![Record::fetch(1, 1)]).unwrap();
+        assert!(delivered[0].is_send() && delivered[0].message == [9; 4]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn store_sizes<const N: usize>(l: &LoadBalancer<N>) -> Vec<u64> {
+        l.submaps
+            .iter()
+            .map(|submap| {
+                let mut bytes = Vec::new();
+                submap.encode(&mut bytes).unwrap();
+                u64::from_le_bytes(bytes[..8].try_into().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rebalance_size() {
+        let num_users = 64;
+        let mut l: LoadBalancer<4> =
+            LoadBalancer::new(num_users, 10, 4, UserKeys::generate()).unwrap();
+        let sends = (0..num_users as Uid)
+            .map(|uid| Record::send(uid, [uid as u8; 4]))
+            .collect();
+        MessageStore::batch_send(&mut l, sends).unwrap();
+        let sizes = store_sizes(&l);
+
+        // moving the messages drops the padding they were stored with
+        for _ in 0..4 {
+            l.rotate_prf().unwrap();
+            assert_eq!(store_sizes(&l), sizes);
+        }
+        l.resize(2).unwrap();
+        let shrunk = store_sizes(&l);
+        l.rotate_prf().unwrap();
+        assert_eq!(store_sizes(&l), shrunk);
+        l.resize(4).unwrap();
+        assert_eq!(store_sizes(&l), sizes);
+
+        let fetches = (0..num_users as Uid)
+            .map(|uid| Record::fetch(uid, 1))
+            .collect();
+        let delivered = MessageStore::batch_fetch(&mut l, fetches).unwrap();
+        assert!(delivered
+            .iter()
+            .all(|r| r.is_send() && r.message[0] == r.uid as u8));
+    }
+
+    #[test]
+    fn test_rotate_prf() {
+        let mut l: LoadBalancer<4> = LoadBalancer::new(4, 3, 2, UserKeys::generate()).unwrap();
+        // many more messages for one user than a submap's share of the padding
+        let sends = (0..64).map(|x| Record::send(1, [x; 4])).collect();
+        MessageStore::batch_send(&mut l, sends).unwrap();
+
+        for epoch in 1..=3 {
+            l.rotate_prf().unwrap();
+            assert_eq!(l.epoch(), epoch);
+        }
+
+        let delivered = MessageStore::batch_fetch(&mut l, vec![Record::fetch(1, 64)]).unwrap();
+        let mut messages: Vec<u8> = delivered
+            .iter()
+            .filter(|r| r.is_send())
+            .map(|r| r.message[0])
+            .collect();
+        messages.sort();
+        assert_eq!(messages, (0..64).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_rotate_after_padding() {
+        let num_users = 8;
+        let mut l: LoadBalancer<4> =
+            LoadBalancer::new(num_users, 3, 2, UserKeys::generate()).unwrap();
+        let sends = |x: u8| {
+            (0..num_users as Uid)
+                .map(|uid| Record::send(uid, [x; 4]))
+                .collect()
+        };
+        // the store holds padding sends and the dummies of answered fetches
+        MessageStore::batch_send(&mut l, sends(1)).unwrap();
+        let fetches = (0..4).map(|uid| Record::fetch(uid, 1)).collect();
+        MessageStore::batch_fetch(&mut l, fetches).unwrap();
+        MessageStore::batch_send(&mut l, sends(2)).unwrap();
+
+        l.rotate_prf().unwrap();
+        l.rotate_prf().unwrap();
+
+        let fetches = (0..num_users as Uid)
+            .map(|uid| Record::fetch(uid, 2))
+            .collect();
+        let delivered = MessageStore::batch_fetch(&mut l, fetches).unwrap();
+        for uid in 0..num_users as Uid {
+            let mut messages: Vec<u8> = delivered
+                .iter()
+                .filter(|r| r.uid == uid && r.is_send())
+                .map(|r| r.message[0])
+                .collect();
+            messages.sort();
+            let expected = if uid < 4 { vec![2] } else { vec![1, 2] };
+            assert_eq!(messages, expected, "user {}", uid);
+        }
+    }
+
+    #[test]
+    fn test_retention_after_rotation() {
+        let mut l: LoadBalancer<4> = LoadBalancer::new(4, 3, 2, UserKeys::generate()).unwrap();
+        l.retention = Retention::Rounds(2);
+        MessageStore::batch_send(&mut l, vec![Record::send(1, [1; 4])]).unwrap();
+        l.next_round();
+        MessageStore::batch_send(&mut l, vec![Record::send(1, [2; 4])]).unwrap();
+
+        // moving the messages does not renew them
+        l.next_round();
+        l.rotate_prf().unwrap();
+        l.collect_garbage().unwrap();
+
+        let delivered = MessageStore::batch_fetch(&mut l, vec![Record::fetch(1, 2)]).unwrap();
+        let messages: Vec<u8> = delivered
+            .iter()
+            .filter(|r| r.is_send())
+            .map(|r| r.message[0])
+            .collect();
+        assert_eq!(messages, vec![2]);
+    }
+
+    #[test]
+    fn test_redeliver() {
+        let keys = UserKeys::generate();
+        let mut l: LoadBalancer<8> = LoadBalancer::new(4, 6, 2, keys.clone()).unwrap();
+        l.ack_timeout = Some(1);
+        l.quota = Some(1);
+
+        let fetch = |l: &mut LoadBalancer<8>| -> Vec<(u64, u8)> {
+            l.batch_fetch(vec![Record::fetch(1, 1)])
+                .unwrap()
+                .iter()
+                .map(|envelope| envelope.open_delivery::<8>(&keys.key(1)).unwrap())
+                .filter(|record| record.is_send())
+                .map(|record| (record.seq, record.message[0]))
+                .collect()
+        };
+        let send = Envelope::seal_send(&keys.key(0), 0, 1, 1, &[5; 8]);
+        assert_eq!(l.batch_send(vec![send]).unwrap(), vec![true]);
+        assert_eq!(fetch(&mut l), vec![(1, 5)]);
+        assert_eq!(l.num_pending(), 1);
+
+        // the delivery is lost in transit and never acknowledged
+        l.redeliver().unwrap();
+        l.next_round();
+        l.redeliver().unwrap();
+        assert_eq!(l.num_pending(), 0);
+        assert_eq!(fetch(&mut l), vec![(1, 5)]);
+
+        l.batch_ack(vec![(1, 1)], 2).unwrap();
+        l.next_round();
+        l.redeliver().unwrap();
+        assert_eq!(fetch(&mut l), Vec::<(u64, u8)>::new());
+    }
+
+    #[test]
+    fn test_quota() {
+        let keys = UserKeys::generate();
+        let mut l: LoadBalancer<8> = LoadBalancer::new(4, 6, 2, keys.clone()).unwrap();
+        l.quota = Some(2);
+
+        let send = |x: u8| Envelope::seal_send(&keys.key(0), 0, 1, x as u64, &[x; 8]);
+        assert_eq!(
+            l.batch_send(vec![send(1), send(2), send(3)]).unwrap(),
+            vec![true, true, false]
         );
-        // let end = std::time::SystemTime::now()
-        //     .duration_since(UNIX_EPOCH)
-        //     .unwrap()
-        //     .as_nanos();
-        // println!("final: {}", end - start);
 
-        responses.drain(0..num_requests).map(|r| r.0).collect()
+        let delivered = l.batch_fetch(vec![Record::fetch(1, 2)]).unwrap();
+        let mut messages: Vec<u8> = delivered
+            .iter()
+            .map(|envelope| envelope.open_delivery::<8>(&keys.key(1)).unwrap())
+            .filter(|record| record.is_send())
+            .map(|record| record.message[0])
+            .collect();
+        messages.sort();
+        assert_eq!(messages, vec![1, 2]);
+
+        assert_eq!(l.batch_send(vec![send(4)]).unwrap(), vec![true]);
+    }
+
+    #[test]
+    fn test_quota_near_counter_limit() {
+        let mut l: LoadBalancer<4> = LoadBalancer::new(4, 3, 2, UserKeys::generate()).unwrap();
+        l.quota = Some(1);
+        let entry = l
+            .user_store
+            .iter_mut()
+            .find(|r| !r.0.padding && r.0.uid == 1)
+            .unwrap();
+        entry.0.last_fetch = u32::MAX - 1;
+        entry.0.last_send = u32::MAX - 1;
+
+        let sends = vec![Record::send(1, [1; 4]), Record::send(1, [2; 4])];
+        assert_eq!(
+            MessageStore::batch_send(&mut l, sends).unwrap(),
+            vec![true, false]
+        );
+    }
+
+    #[test]
+    fn test_fetch_near_counter_limit() {
+        let mut l: LoadBalancer<4> = LoadBalancer::new(4, 3, 2, UserKeys::generate()).unwrap();
+        let entry = l
+            .user_store
+            .iter_mut()
+            .find(|r| !r.0.padding && r.0.uid == 1)
+            .unwrap();
+        entry.0.last_fetch = u32::MAX - 1;
+        entry.0.last_send = u32::MAX - 1;
+
+        let delivered = MessageStore::batch_fetch(&mut l, vec![Record::fetch(1, 3)]).unwrap();
+        assert_eq!(delivered.len(), 3);
+        assert!(delivered.iter().all(|r| !r.is_send()));
+    }
+
+    #[test]
+    fn test_group_send_without_slots() {
+        let mut l: LoadBalancer<4> = LoadBalancer::new(4, 3, 2, UserKeys::generate()).unwrap();
+        l.groups = GroupTable::new(0);
+        assert!(matches!(
+            l.batch_group_send(vec![Envelope::dummy()]),
+            Err(SpartaError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_register() {
+        let keys = UserKeys::generate();
+        let mut l: LoadBalancer<8> = LoadBalancer::new(4, 6, 2, keys.clone()).unwrap();
+
+        let uids = l.batch_register(2, 5).unwrap();
+        assert_eq!(l.user_store.len(), 9);
+        assert_eq!(l.user_store.iter().filter(|r| r.0.padding).count(), 3);
+
+        let (alice, bob) = (uids[0], uids[1]);
+        let envelope = Envelope::seal_send(&keys.key(alice), alice, bob, 1, &[5; 8]);
+        assert_eq!(l.batch_send(vec![envelope]).unwrap(), vec![true]);
+        let delivered = l.batch_fetch(vec![Record::fetch(bob, 1)]).unwrap();
+        let record = delivered[0].open_delivery::<8>(&keys.key(bob)).unwrap();
+        assert!(record.is_send());
+        assert_eq!((record.sender, record.seq), (alice, 1));
+
+        l.batch_unregister(vec![alice], 3).unwrap();
+        assert!(matches!(
+            l.batch_unregister(vec![bob], 0),
+            Err(SpartaError::InvalidRequest(_))
+        ));
+        assert_eq!(l.user_store.len(), 6);
+        assert!(l.user_store.iter().any(|r| r.0.uid == bob && !r.0.padding));
+
+        let envelope = Envelope::seal_send(&keys.key(bob), bob, alice, 1, &[6; 8]);
+        assert_eq!(l.batch_send(vec![envelope]).unwrap(), vec![false]);
+    }
+
+    #[test]
+    fn test_register_collision() {
+        let mut l: LoadBalancer<4> = LoadBalancer::new(4, 3, 2, UserKeys::generate()).unwrap();
+        assert!(!l.register_ids(&[7, 1], 2));
+        assert!(!l.register_ids(&[7, 7], 2));
+        assert_eq!(l.user_store.len(), 4);
+        assert_eq!(l.num_users, 4);
+        assert!(l.user_store.iter().all(|r| !r.0.padding && r.0.uid < 4));
+
+        // phantoms never collide, since no client can address them
+        assert!(l.register_ids(&[7, 1], 1));
+        assert_eq!(l.user_store.len(), 6);
+    }
+
+    #[test]
+    fn test_restore() {
+        let path = std::env::temp_dir().join(format!("sparta-restore-test-{}", std::process::id()));
+        let key = [3; SEALING_KEY_SIZE];
+        let mut l: LoadBalancer<8> = LoadBalancer::new(4, 6, 2, UserKeys::generate()).unwrap();
+        l.lambda = 40;
+        l.ack_timeout = Some(3);
+        l.snapshot(&path, &key, 1).unwrap();
+
+        let restored: LoadBalancer<8> = LoadBalancer::restore(&path, &key, 1, 6).unwrap();
+        assert_eq!(restored.user_store.len(), 4);
+        assert_eq!(restored.lambda, 40);
+        assert_eq!(restored.ack_timeout, Some(3));
+        assert!(matches!(
+            LoadBalancer::<16>::restore(&path, &key, 1, 6),
+            Err(SpartaError::MessageSizeMismatch {
+                expected: 16,
+                found: 8
+            })
+        ));
+        assert!(matches!(
+            LoadBalancer::<8>::restore(&path, &key, 2, 6),
+            Err(SpartaError::Io(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_restore_with_submaps() {
+        let path = std::env::temp_dir().join(format!(
+            "sparta-restore-submaps-test-{}",
+            std::process::id()
+        ));
+        let key = [3; SEALING_KEY_SIZE];
+        let mut l: LoadBalancer<4> = LoadBalancer::new(4, 6, 2, UserKeys::generate()).unwrap();
+        MessageStore::batch_send(&mut l, vec![Record::send(1, [9; 4])]).unwrap();
+        l.snapshot(&path, &key, 1).unwrap();
+
+        let submaps = |count: usize| -> Vec<Box<dyn Submap<4>>> {
+            (0..count)
+                .map(|_| Box::new(ObliviousMap::new(1).unwrap()) as Box<dyn Submap<4>>)
+                .collect()
+        };
+        assert!(matches!(
+            LoadBalancer::<4>::restore_with_submaps(&path, &key, 1, 6, submaps(1)),
+            Err(SpartaError::InvalidConfig(_))
+        ));
+        let mut restored =
+            LoadBalancer::<4>::restore_with_submaps(&path, &key, 1, 6, submaps(2)).unwrap();
+        let delivered =
+            MessageStore::batch_fetch(&mut restored, vec![Record::fetch(1, 1)]).unwrap();
+        assert!(delivered[0].is_send() && delivered[0].message == [9; 4]);
+        std::fs::remove_file(&path).unwrap();
     }
 }
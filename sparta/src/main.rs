@@ -1,16 +1,39 @@
-mod load_balancer;
-mod omap;
-mod record;
+mod scheduler;
+mod server;
 
-use clap::Parser;
-use load_balancer::LoadBalancer;
-use record::Record;
-use std::time::UNIX_EPOCH;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use scheduler::RoundConfig;
+use server::Server;
+use sparta::{
+    Backend, Envelope, GroupTable, LoadBalancer, Record, Result, Retention, SpartaError, Uid,
+    UserKeys, DEFAULT_LAMBDA, DEFAULT_MAX_GROUP_SIZE, DEFAULT_MESSAGE_SIZE,
+};
+use std::{
+    fs, io,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
 
 /// Baseline oblivious sort based multiqueue.
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    benchmark: Option<BenchmarkArgs>,
+}
+
+#[derive(clap::Args, Debug)]
+struct BenchmarkArgs {
     /// Number of send requests to store in the database.
     sends: usize,
 
@@ -33,41 +56,283 @@ struct Args {
     /// Number of runs before measurements are recorded.
     #[arg(short, long, default_value = "0")]
     warmup_runs: usize,
+
+    /// Size in bytes of each message payload (96, 256, 1024 or 4096).
+    #[arg(short = 's', long, default_value_t = DEFAULT_MESSAGE_SIZE)]
+    message_size: usize,
+
+    /// Message store to benchmark: parallel, sequential or single-queue.
+    #[arg(short, long, default_value_t = Backend::Parallel)]
+    backend: Backend,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve send and fetch requests over TCP in fixed-size rounds.
+    Serve(ServeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// Address to listen on.
+    addr: String,
+
+    /// Total number of threads available.
+    threads: usize,
+
+    /// Number of users in the user store.
+    users: usize,
+
+    /// Number of submaps.
+    maps: usize,
+
+    /// Longest a round stays open, in milliseconds.
+    #[arg(short, long, default_value = "100")]
+    round_ms: u64,
+
+    /// Number of sends in every round, padded with dummies.
+    #[arg(long, default_value = "1024")]
+    send_slots: usize,
+
+    /// Number of messages fetched in every round, padded with dummies.
+    #[arg(long, default_value = "1024")]
+    fetch_slots: usize,
+
+    /// Number of group sends in every round, padded with dummies; 0 disables
+    /// group sends.
+    #[arg(long, default_value = "0")]
+    group_slots: usize,
+
+    /// Number of acknowledgements in every round, padded with dummies.
+    #[arg(long, default_value = "1024")]
+    ack_slots: usize,
+
+    /// Number of rounds a delivery waits for its recipient's acknowledgement
+    /// before it is delivered again; 0 makes every delivery final and ignores
+    /// acknowledgements.
+    #[arg(long, default_value = "0")]
+    ack_timeout: u64,
+
+    /// Number of member slots every group is padded to.
+    #[arg(long, default_value_t = DEFAULT_MAX_GROUP_SIZE)]
+    max_group_size: usize,
+
+    /// File listing one group per line: the group id followed by its members.
+    #[arg(short, long)]
+    groups: Option<PathBuf>,
+
+    /// Number of rounds an undelivered message is kept; 0 keeps it forever.
+    #[arg(long, default_value = "0")]
+    retention: u64,
+
+    /// Largest number of undelivered messages a user may have queued; 0 means
+    /// no limit.
+    #[arg(long, default_value = "0")]
+    quota: u32,
+
+    /// Statistical security parameter: batches reveal that they overflowed a
+    /// submap's padding with probability at most 2^-lambda.
+    #[arg(long, default_value_t = DEFAULT_LAMBDA)]
+    lambda: usize,
+
+    /// Number of rounds between garbage collection passes.
+    #[arg(long, default_value = "100")]
+    gc_interval: u64,
+
+    /// Number of rounds in a PRF epoch, after which the key that maps messages
+    /// to submaps is replaced; 0 keeps one key for good.
+    #[arg(long, default_value = "1000")]
+    rotate_interval: u64,
+
+    /// File holding the master key clients' keys are derived from; created if missing.
+    #[arg(short, long, default_value = "master.key")]
+    key_file: PathBuf,
+
+    /// Size in bytes of each message payload (96, 256, 1024 or 4096).
+    #[arg(short = 's', long, default_value_t = DEFAULT_MESSAGE_SIZE)]
+    message_size: usize,
+}
+
+fn load_keys(path: &Path) -> io::Result<UserKeys> {
+    match fs::read(path) {
+        Ok(master) => master
+            .try_into()
+            .map(UserKeys::new)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad master key")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keys = UserKeys::generate();
+            fs::write(path, keys.master())?;
+            Ok(keys)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn load_groups<const N: usize>(path: &Path, max_group_size: usize) -> Result<GroupTable<N>> {
+    let invalid = |msg| SpartaError::Io(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+    let mut groups = GroupTable::new(max_group_size);
+    for line in fs::read_to_string(path)?.lines() {
+        let ids = line
+            .split_whitespace()
+            .map(|id| id.parse().map_err(|_| invalid("bad group id")))
+            .collect::<Result<Vec<Uid>>>()?;
+        if let Some((gid, members)) = ids.split_first() {
+            groups.set(*gid, members)?;
+        }
+    }
+    Ok(groups)
+}
+
+/// What to run, once the command line is known to hold either a subcommand
+/// or the benchmark arguments.
+enum Mode {
+    Serve(ServeArgs),
+    Benchmark(BenchmarkArgs),
 }
 
 fn main() {
     let args = Args::parse();
+    let mode = match (args.command, args.benchmark) {
+        (Some(Command::Serve(serve)), _) => Mode::Serve(serve),
+        (None, Some(benchmark)) => Mode::Benchmark(benchmark),
+        (None, None) => Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the benchmark arguments are required without a subcommand",
+            )
+            .exit(),
+    };
 
-    let mut l = LoadBalancer::new(args.users as i64, args.threads, args.maps);
-    let sends: Vec<Record> = (0..args.sends)
-        .map(|x| Record::send(0 as i64, x.try_into().unwrap()))
-        .collect();
+    let message_size = match &mode {
+        Mode::Serve(serve) => serve.message_size,
+        Mode::Benchmark(benchmark) => benchmark.message_size,
+    };
+    let result = match message_size {
+        96 => run::<96>(mode),
+        256 => run::<256>(mode),
+        1024 => run::<1024>(mode),
+        4096 => run::<4096>(mode),
+        _ => Err(SpartaError::InvalidConfig("unsupported message size")),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    l.batch_send(sends);
+fn run<const N: usize>(mode: Mode) -> Result<()> {
+    match mode {
+        Mode::Serve(serve) => run_server::<N>(serve),
+        Mode::Benchmark(benchmark) => run_benchmark::<N>(benchmark),
+    }
+}
+
+fn run_server<const N: usize>(args: ServeArgs) -> Result<()> {
+    let keys = load_keys(&args.key_file)?;
+    let mut l: LoadBalancer<N> = LoadBalancer::new(args.users, args.threads, args.maps, keys)?;
+    l.groups = match &args.groups {
+        Some(path) => load_groups(path, args.max_group_size)?,
+        None => GroupTable::new(args.max_group_size),
+    };
+    if args.retention > 0 {
+        l.retention = Retention::Rounds(args.retention);
+    }
+    if args.quota > 0 {
+        l.quota = Some(args.quota);
+    }
+    l.lambda = args.lambda;
+    if args.ack_timeout > 0 {
+        l.ack_timeout = Some(args.ack_timeout);
+    }
+    eprintln!("send batches: {}", l.padding(args.send_slots));
+    eprintln!("fetch batches: {}", l.padding(args.fetch_slots));
+    if args.group_slots > 0 {
+        let copies = args.group_slots * args.max_group_size;
+        eprintln!("group send batches: {}", l.padding(copies));
+    }
+    let listener = TcpListener::bind(&args.addr)?;
+    let config = RoundConfig {
+        interval: Duration::from_millis(args.round_ms),
+        send_slots: args.send_slots,
+        fetch_slots: args.fetch_slots,
+        group_slots: args.group_slots,
+        ack_slots: if args.ack_timeout > 0 {
+            args.ack_slots
+        } else {
+            0
+        },
+        gc_interval: args.gc_interval,
+        rotate_interval: args.rotate_interval,
+    };
+    Server::new(l, config).run(listener)
+}
 
-    let results: Vec<f64> = (0..(args.runs + args.warmup_runs))
+/// Times `warmup_runs + runs` fetches, one after another.
+fn time_fetches(
+    runs: usize,
+    warmup_runs: usize,
+    mut fetch: impl FnMut() -> Result<()>,
+) -> Result<Vec<f64>> {
+    (0..(runs + warmup_runs))
         .map(|_| {
             let start = std::time::SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs_f64();
-            let _responses = l.batch_fetch(vec![Record::fetch(0, args.fetches)]);
+            fetch()?;
             let end = std::time::SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs_f64();
+            Ok(end - start)
+        })
+        .collect()
+}
 
-            // for response in responses.iter() {
-            //     println!("{:?}", response);
-            // }
+/// Benchmarks the load balancer backends as clients reach them: sends are
+/// sealed envelopes and every delivery is sealed for its recipient. The single
+/// queue has no envelope layer and stores plaintext records.
+fn run_benchmark<const N: usize>(args: BenchmarkArgs) -> Result<()> {
+    let keys = UserKeys::generate();
+    let results = match args.backend.open_load_balancer::<N>(
+        args.users,
+        args.threads,
+        args.maps,
+        keys.clone(),
+    )? {
+        Some(mut l) => {
+            let key = keys.key(0);
+            let sends: Vec<Envelope> = (0..args.sends)
+                .map(|x| Envelope::seal_send::<N>(&key, 0, 0, x as u64 + 1, &sparta::message(x)))
+                .collect();
+            l.batch_send(sends)?;
 
-            end - start
-        })
-        .collect();
+            time_fetches(args.runs, args.warmup_runs, || {
+                l.batch_fetch(vec![Record::fetch(0, args.fetches)])?;
+                Ok(())
+            })?
+        }
+        None => {
+            let mut store = args
+                .backend
+                .open::<N>(args.users, args.threads, args.maps)?;
+            let sends: Vec<Record<N>> = (0..args.sends)
+                .map(|x| Record::send(0, sparta::message(x)))
+                .collect();
+            store.batch_send(sends)?;
+
+            time_fetches(args.runs, args.warmup_runs, || {
+                store.batch_fetch(vec![Record::fetch(0, args.fetches)])?;
+                Ok(())
+            })?
+        }
+    };
 
     print!("{}\t", args.sends);
     for result in results[..].iter() {
         print!("{}\t", *result);
     }
     println!();
+    Ok(())
 }
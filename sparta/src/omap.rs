@@ -1,13 +1,25 @@
-use crate::record::{IndexRecord, Record, RecordType};
+use crate::error::Result;
+use crate::record::{Record, RecordType};
 use otils::{Max, ObliviousOps};
 use rayon::ThreadPool;
-use std::cmp::Ordering;
+use std::{cmp::Ordering, collections::VecDeque};
 
-struct MapRecord(Record);
+/// How long an undelivered message is kept, counted in load balancer rounds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retention {
+    Forever,
+    Rounds(u64),
+}
+
+#[derive(Clone)]
+struct MapRecord<const N: usize>(Record<N>);
 
-impl MapRecord {
-    fn dummy_send(idx: u32) -> Self {
-        MapRecord(Record::new(0, RecordType::Dummy, 0, 0, idx))
+impl<const N: usize> MapRecord<N> {
+    fn dummy_send(fetch: &Record<N>, round: u64) -> Self {
+        let mut record = Record::new(fetch.uid, RecordType::Dummy, 0, 0, fetch.idx);
+        record.padding = fetch.padding;
+        record.round = round;
+        MapRecord(record)
     }
 
     fn should_deliver(&self) -> bool {
@@ -17,75 +29,177 @@ impl MapRecord {
     fn should_defer(&self) -> bool {
         !self.0.is_fetch() && self.0.mark == 0
     }
+
+    fn is_live(&self, oldest_round: u64) -> bool {
+        self.0.is_send() && self.0.round >= oldest_round
+    }
 }
 
-impl PartialEq for MapRecord {
+impl<const N: usize> PartialEq for MapRecord<N> {
     fn eq(&self, other: &Self) -> bool {
-        self.0.idx == other.0.idx && self.0.rec_type == other.0.rec_type
+        self.0.idx == other.0.idx
+            && self.0.rec_type == other.0.rec_type
+            && self.0.padding == other.0.padding
     }
 }
 
-impl PartialOrd for MapRecord {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        let idx_ord = self.0.idx.partial_cmp(&other.0.idx);
-        let type_ord = self.0.rec_type.partial_cmp(&other.0.rec_type);
-        match idx_ord {
-            Some(Ordering::Equal) => type_ord,
-            x => x,
-        }
+// padding sorts after the dummies of user fetches sharing its index, so those
+// fetches are answered with their own dummies
+impl<const N: usize> PartialOrd for MapRecord<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let key = (self.0.idx, self.0.rec_type.clone() as u8, self.0.padding);
+        let other_key = (other.0.idx, other.0.rec_type.clone() as u8, other.0.padding);
+        key.partial_cmp(&other_key)
     }
 }
 
-impl Max for MapRecord {
+impl<const N: usize> Max for MapRecord<N> {
     fn maximum() -> Self {
-        MapRecord(Record::new(0, RecordType::Dummy, 0, 0, u32::MAX))
+        MapRecord(Record::padding(RecordType::Dummy, 0, 0))
     }
 }
 
-pub struct ObliviousMap {
+type History = VecDeque<(u64, usize)>;
+
+pub struct ObliviousMap<const N: usize> {
     num_threads: usize,
     pool: ThreadPool,
-    message_store: Vec<MapRecord>,
+    message_store: Vec<MapRecord<N>>,
+
+    // number of records stored per round, a public function of the batch sizes
+    history: History,
+
+    // store and history as of the last commit, while a batch is in flight
+    checkpoint: Option<(Vec<MapRecord<N>>, History)>,
 }
 
-impl ObliviousMap {
-    pub fn new(num_threads: usize) -> Self {
+impl<const N: usize> ObliviousMap<N> {
+    pub fn new(num_threads: usize) -> Result<Self> {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(num_threads)
-            .build()
-            .unwrap();
+            .build()?;
 
         let message_store = Vec::new();
-        ObliviousMap {
+        Ok(ObliviousMap {
             num_threads,
             pool,
             message_store,
+            history: VecDeque::new(),
+            checkpoint: None,
+        })
+    }
+
+    /// Keeps a copy of the store to roll back to, once per transaction. The
+    /// copy is linear in the store, below the sorts each batch runs over it.
+    fn begin(&mut self) {
+        if self.checkpoint.is_none() {
+            self.checkpoint = Some((self.message_store.clone(), self.history.clone()));
         }
     }
 
-    pub fn batch_send(&mut self, requests: Vec<Record>) {
+    /// Makes every batch since the last commit permanent.
+    pub fn commit(&mut self) {
+        self.checkpoint = None;
+    }
+
+    /// Undoes every batch since the last commit, including one that was
+    /// interrupted halfway.
+    pub fn rollback(&mut self) {
+        if let Some((message_store, history)) = self.checkpoint.take() {
+            self.message_store = message_store;
+            self.history = history;
+        }
+    }
+
+    /// Stores a batch of requests sent in `round`. Each request keeps the
+    /// round in its own `round` field, which may be older for messages moved
+    /// over from another submap.
+    pub fn batch_send(&mut self, requests: Vec<Record<N>>, round: u64) {
+        self.begin();
         // println!("num sends {}", requests.len());
+        match self.history.back_mut() {
+            Some((last, count)) if *last == round => *count += requests.len(),
+            _ => self.history.push_back((round, requests.len())),
+        }
+
         self.message_store.reserve(requests.len());
         self.message_store
             .extend(requests.into_iter().map(|r| MapRecord(r)));
     }
 
-    fn update_with_fetches(&mut self, requests: Vec<Record>) {
-        self.message_store.reserve(2 * requests.len());
+    pub fn len(&self) -> usize {
+        self.message_store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.message_store.is_empty()
+    }
 
-        // add padding for fetches
-        self.message_store.extend(
-            requests
-                .iter()
-                .map(|record| MapRecord::dummy_send(record.idx)),
+    pub fn iter(&self) -> impl Iterator<Item = &Record<N>> {
+        self.message_store.iter().map(|r| &r.0)
+    }
+
+    pub fn drain(&mut self) -> Vec<Record<N>> {
+        self.begin();
+        self.history.clear();
+        self.message_store.drain(..).map(|r| r.0).collect()
+    }
+
+    /// Evicts messages stored before `oldest_round`. The store shrinks to the
+    /// number of records sent since then, which only depends on batch sizes,
+    /// so the pass does not reveal how many messages were still undelivered.
+    /// Messages refilled by a rebalance or a restore count as sent in that
+    /// round, which can only keep the store larger; each message still expires
+    /// by the round it was first stored in.
+    pub fn collect_garbage(&mut self, oldest_round: u64) {
+        self.begin();
+        for record in self.message_store.iter_mut() {
+            let is_expired = record.0.is_send() && !record.is_live(oldest_round);
+            record.0.rec_type = RecordType::from_u8(u8::oselect(
+                is_expired,
+                RecordType::Dummy as u8,
+                record.0.rec_type.clone() as u8,
+            ))
+            .unwrap();
+        }
+
+        while let Some((round, _)) = self.history.front() {
+            if *round >= oldest_round {
+                break;
+            }
+            self.history.pop_front();
+        }
+        let final_size = self
+            .history
+            .iter()
+            .fold(0, |acc, (_, count)| acc + count)
+            .min(self.message_store.len());
+
+        otils::compact(
+            &mut self.message_store[..],
+            |r| r.is_live(oldest_round),
+            &self.pool,
+            self.num_threads,
         );
+        self.message_store.truncate(final_size);
+    }
+
+    fn update_with_fetches(&mut self, requests: Vec<Record<N>>) {
+        self.message_store.reserve(2 * requests.len());
+
+        // add padding for fetches, as recent as the newest messages so it never
+        // looks older than what it may stand in for
+        let round = self.history.back().map_or(0, |(round, _)| *round);
+        self.message_store
+            .extend(requests.iter().map(|r| MapRecord::dummy_send(r, round)));
 
         // add fetches
         self.message_store
             .extend(requests.into_iter().map(|r| MapRecord(r)));
     }
 
-    pub fn batch_fetch(&mut self, requests: Vec<Record>) -> Vec<IndexRecord> {
+    pub fn batch_fetch(&mut self, requests: Vec<Record<N>>) -> Vec<Record<N>> {
+        self.begin();
         // println!("num fetches {}", requests.len());
 
         let final_size = self.message_store.len();
@@ -118,7 +232,7 @@ impl ObliviousMap {
         let response = self
             .message_store
             .drain(0..num_requests)
-            .map(|r| IndexRecord(r.0))
+            .map(|r| r.0)
             .collect();
 
         otils::compact(
@@ -131,3 +245,62 @@ impl ObliviousMap {
         response
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Uid;
+
+    fn stamped(uid: Uid, round: u64) -> Record<4> {
+        let mut record = Record::send(uid, [uid as u8; 4]);
+        record.round = round;
+        record
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut omap: ObliviousMap<4> = ObliviousMap::new(1).unwrap();
+        omap.batch_send(vec![stamped(1, 0), stamped(2, 0)], 0);
+        omap.batch_send(vec![stamped(3, 1), stamped(4, 1), stamped(5, 1)], 1);
+
+        omap.collect_garbage(1);
+        let mut live: Vec<Uid> = omap.iter().map(|r| r.uid).collect();
+        live.sort();
+        assert_eq!(live, vec![3, 4, 5]);
+
+        omap.collect_garbage(2);
+        assert_eq!(omap.len(), 0);
+    }
+
+    #[test]
+    fn test_refilled_rounds() {
+        let mut omap: ObliviousMap<4> = ObliviousMap::new(1).unwrap();
+        omap.batch_send(vec![stamped(1, 0), stamped(2, 1)], 0);
+        omap.batch_send(vec![stamped(3, 1)], 1);
+
+        // a rebalance in round 2 refills the map with the same messages
+        let drained = omap.drain();
+        omap.batch_send(drained, 2);
+
+        // the store keeps its size, but the expired messages are gone
+        omap.collect_garbage(1);
+        assert_eq!(omap.len(), 3);
+        let mut live: Vec<Uid> = omap.iter().filter(|r| r.is_send()).map(|r| r.uid).collect();
+        live.sort();
+        assert_eq!(live, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut omap: ObliviousMap<4> = ObliviousMap::new(1).unwrap();
+        omap.batch_send(vec![stamped(1, 0)], 0);
+        omap.commit();
+
+        let fetch = || vec![Record::new(1, RecordType::Fetch, 0, 0, 0)];
+        assert!(omap.batch_fetch(fetch())[0].is_send());
+        omap.rollback();
+        assert!(omap.batch_fetch(fetch())[0].is_send());
+        omap.commit();
+        assert!(!omap.batch_fetch(fetch())[0].is_send());
+    }
+}
@@ -0,0 +1,247 @@
+use crate::error::{Result, SpartaError};
+use crate::record::{Record, RecordType, Uid};
+use crate::store::{validate_fetches, validate_sends, MessageStore};
+use otils::{Max, ObliviousOps};
+use rayon::ThreadPool;
+use std::cmp::Ordering;
+
+/// Record in the multiqueue. A user's fetches sort first, then its messages
+/// in the order they were sent, then its dummies.
+struct QueueRecord<const N: usize>(Record<N>);
+
+impl<const N: usize> QueueRecord<N> {
+    fn dummies(fetch: &Record<N>) -> Vec<Self> {
+        (0..fetch.data)
+            .map(|_| {
+                let mut record = Record::new(fetch.uid, RecordType::Dummy, 0, 0, 0);
+                record.padding = fetch.padding;
+                QueueRecord(record)
+            })
+            .collect()
+    }
+
+    fn should_deliver(&self) -> bool {
+        !self.0.is_fetch() && self.0.mark == 1
+    }
+
+    fn should_defer(&self) -> bool {
+        !self.0.is_fetch() && self.0.mark == 0
+    }
+
+    fn key(&self) -> (bool, Uid, u8, u64) {
+        (
+            self.0.padding,
+            self.0.uid,
+            self.0.rec_type.clone() as u8,
+            self.0.data,
+        )
+    }
+}
+
+impl<const N: usize> PartialEq for QueueRecord<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<const N: usize> PartialOrd for QueueRecord<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.key().partial_cmp(&other.key())
+    }
+}
+
+impl<const N: usize> Max for QueueRecord<N> {
+    fn maximum() -> Self {
+        QueueRecord(Record::padding(RecordType::Dummy, u64::MAX, 0))
+    }
+}
+
+/// Baseline store: every message sits in one queue that is obliviously sorted
+/// on each fetch, with no load balancer or submaps.
+pub struct ObliviousMultiQueue<const N: usize> {
+    num_threads: usize,
+    pool: ThreadPool,
+    message_store: Vec<QueueRecord<N>>,
+    next_seq: u64,
+}
+
+impl<const N: usize> ObliviousMultiQueue<N> {
+    pub fn new(num_threads: usize) -> Result<Self> {
+        if num_threads == 0 {
+            return Err(SpartaError::InvalidConfig(
+                "at least one thread is required",
+            ));
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()?;
+        Ok(ObliviousMultiQueue {
+            num_threads,
+            pool,
+            message_store: Vec::new(),
+            next_seq: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.message_store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.message_store.is_empty()
+    }
+
+    fn update_store(&mut self, fetches: Vec<Record<N>>, fetch_sum: usize) {
+        self.message_store.reserve(fetches.len() + fetch_sum);
+
+        for fetch in fetches.iter() {
+            self.message_store.extend(QueueRecord::dummies(fetch));
+        }
+
+        self.message_store
+            .extend(fetches.into_iter().map(QueueRecord));
+    }
+}
+
+impl<const N: usize> MessageStore<N> for ObliviousMultiQueue<N> {
+    /// Appends the sends, stamped with their arrival order. Every send is
+    /// stored.
+    fn batch_send(&mut self, sends: Vec<Record<N>>) -> Result<Vec<bool>> {
+        validate_sends(&sends)?;
+
+        let stored = vec![true; sends.len()];
+
+        self.message_store.reserve(sends.len());
+        for mut send in sends.into_iter() {
+            send.data = self.next_seq;
+            self.next_seq += 1;
+            self.message_store.push(QueueRecord(send));
+        }
+        Ok(stored)
+    }
+
+    fn batch_fetch(&mut self, fetches: Vec<Record<N>>) -> Result<Vec<Record<N>>> {
+        validate_fetches(&fetches)?;
+
+        let final_size = self.message_store.len();
+        let fetch_sum = fetches.iter().fold(0, |acc, f| acc + f.data as usize);
+        let num_delivered = fetches.iter().fold(0, |acc, f| {
+            acc + usize::oselect(f.padding, 0, f.data as usize)
+        });
+        self.update_store(fetches, fetch_sum);
+
+        self.message_store = otils::sort(
+            std::mem::take(&mut self.message_store),
+            &self.pool,
+            self.num_threads,
+        );
+
+        let mut user_sum: i64 = 0;
+        let mut prev_user = (true, Uid::MAX);
+        for record in self.message_store.iter_mut() {
+            let user = (record.0.padding, record.0.uid);
+            let same_user = prev_user == user;
+            user_sum = i64::oselect(same_user, user_sum, 0);
+
+            let fetch_more = user_sum > 0;
+            record.0.mark = u16::oselect(record.0.is_fetch(), 0, u16::oselect(fetch_more, 1, 0));
+
+            prev_user = user;
+            user_sum += i64::oselect(
+                record.0.is_fetch(),
+                record.0.data as i64,
+                i64::oselect(fetch_more, -1, 0),
+            );
+        }
+
+        otils::compact(
+            &mut self.message_store[..],
+            |r| r.should_deliver(),
+            &self.pool,
+            self.num_threads,
+        );
+        // deliveries stay sorted, so those of padding fetches come last
+        let deliver: Vec<Record<N>> = self
+            .message_store
+            .drain(0..fetch_sum)
+            .take(num_delivered)
+            .map(|r| r.0)
+            .collect();
+
+        otils::compact(
+            &mut self.message_store[..],
+            |r| r.should_defer(),
+            &self.pool,
+            self.num_threads,
+        );
+        self.message_store.truncate(final_size);
+
+        Ok(deliver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(uid: Uid) -> QueueRecord<4> {
+        QueueRecord(Record::send(uid, [0; 4]))
+    }
+
+    fn fetch(uid: Uid) -> QueueRecord<4> {
+        QueueRecord(Record::fetch(uid, 0))
+    }
+
+    #[test]
+    fn test_eq() {
+        let s_less = send(0);
+        let f_less = fetch(0);
+
+        assert!(s_less == s_less);
+        assert!(f_less != s_less);
+        assert!(f_less == f_less);
+    }
+
+    #[test]
+    fn test_ord() {
+        let s_less = send(0);
+        let s_great = send(1);
+        let f_less = fetch(0);
+        let f_great = fetch(1);
+
+        assert!(s_less < s_great);
+        assert!(s_great > s_less);
+        assert!(s_great == s_great);
+
+        assert!(f_less < f_great);
+        assert!(f_great > f_less);
+        assert!(f_great == f_great);
+
+        assert!(f_less < s_less);
+        assert!(f_less < s_great);
+        assert!(f_great < s_great);
+        assert!(f_great > s_less);
+    }
+
+    #[test]
+    fn test_fifo() {
+        let mut o: ObliviousMultiQueue<4> = ObliviousMultiQueue::new(2).unwrap();
+        o.batch_send((0..4).map(|x| Record::send(0, [x; 4])).collect())
+            .unwrap();
+
+        let deliver = o.batch_fetch(vec![Record::fetch(0, 3)]).unwrap();
+        let messages: Vec<u8> = deliver.iter().map(|r| r.message[0]).collect();
+        assert_eq!(messages, vec![0, 1, 2]);
+        assert_eq!(o.len(), 4);
+
+        let deliver = o
+            .batch_fetch(vec![
+                Record::fetch(0, 2),
+                Record::padding(RecordType::Fetch, 2, 0),
+            ])
+            .unwrap();
+        assert_eq!(deliver.len(), 2);
+        assert!(deliver[0].is_send() && deliver[0].message[0] == 3);
+        assert!(!deliver[1].is_send());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::codec::Decoder;
+use crate::record::{Record, RecordType, Uid};
+use otils::{Max, ObliviousOps};
+use rayon::ThreadPool;
+use std::{cmp::Ordering, collections::VecDeque, io};
+
+/// Delivery held for its recipient, or an acknowledgement, which sorts ahead
+/// of the deliveries of the fetch it names.
+#[derive(Clone)]
+struct PendingRecord<const N: usize> {
+    ack: bool,
+    acked: bool,
+    round: u64,
+    record: Record<N>,
+}
+
+impl<const N: usize> PendingRecord<N> {
+    fn delivery(record: Record<N>, round: u64) -> Self {
+        PendingRecord {
+            ack: false,
+            acked: false,
+            round,
+            record,
+        }
+    }
+
+    fn ack(uid: Uid, round: u64) -> Self {
+        PendingRecord {
+            ack: true,
+            acked: false,
+            round,
+            record: Record::new(uid, RecordType::Dummy, 0, 0, 0),
+        }
+    }
+
+    fn fetch(&self) -> (bool, Uid, u64) {
+        (self.record.padding, self.record.uid, self.round)
+    }
+
+    fn key(&self) -> (bool, Uid, u64, bool) {
+        (self.record.padding, self.record.uid, self.round, !self.ack)
+    }
+}
+
+impl<const N: usize> PartialEq for PendingRecord<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<const N: usize> PartialOrd for PendingRecord<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.key().partial_cmp(&other.key())
+    }
+}
+
+impl<const N: usize> Max for PendingRecord<N> {
+    fn maximum() -> Self {
+        PendingRecord {
+            ack: true,
+            acked: false,
+            round: 0,
+            record: Record::padding(RecordType::Dummy, 0, 0),
+        }
+    }
+}
+
+/// Deliveries waiting for their recipient's acknowledgement. Every delivery
+/// of a fetch is held, dummies included, so the number held only depends on
+/// the sizes of earlier fetch batches, and acknowledgements mark deliveries in
+/// place rather than removing them.
+#[derive(Clone)]
+pub(crate) struct PendingDeliveries<const N: usize> {
+    records: Vec<PendingRecord<N>>,
+
+    // number of deliveries held per round, a public function of the batch sizes
+    history: VecDeque<(u64, usize)>,
+}
+
+impl<const N: usize> PendingDeliveries<N> {
+    pub fn new() -> Self {
+        PendingDeliveries {
+            records: Vec::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Holds the deliveries of a fetch batch made in `round`.
+    pub fn hold(&mut self, deliveries: &[Record<N>], round: u64) {
+        match self.history.back_mut() {
+            Some((last, count)) if *last == round => *count += deliveries.len(),
+            _ => self.history.push_back((round, deliveries.len())),
+        }
+
+        self.records.reserve(deliveries.len());
+        self.records.extend(
+            deliveries
+                .iter()
+                .map(|record| PendingRecord::delivery(record.clone(), round)),
+        );
+    }
+
+    /// Marks the deliveries of the fetches named in `acks`, each a user and
+    /// the round it fetched in, as acknowledged, padding the batch to `slots`
+    /// acknowledgements. Deliveries of the user's other fetches stay held.
+    pub fn ack(
+        &mut self,
+        acks: &[(Uid, u64)],
+        slots: usize,
+        pool: &ThreadPool,
+        num_threads: usize,
+    ) {
+        let num_records = self.records.len();
+        self.records.reserve(slots);
+        self.records.extend(
+            acks.iter()
+                .map(|&(uid, round)| PendingRecord::ack(uid, round)),
+        );
+        self.records
+            .extend((acks.len()..slots).map(|_| PendingRecord::maximum()));
+
+        self.records = otils::sort(std::mem::take(&mut self.records), pool, num_threads);
+
+        let mut acked = false;
+        let mut prev = (true, Uid::MAX, u64::MAX);
+        for r in self.records.iter_mut() {
+            let fetch = r.fetch();
+            let is_same_fetch = fetch == prev;
+            prev = fetch;
+
+            acked = u8::oselect(
+                r.ack,
+                !r.record.padding as u8,
+                u8::oselect(is_same_fetch, acked as u8, 0),
+            ) == 1;
+            r.acked = u8::oselect(r.ack, 0, (r.acked || acked) as u8) == 1;
+        }
+
+        otils::compact(&mut self.records[..], |r| !r.ack, pool, num_threads);
+        self.records.truncate(num_records);
+    }
+
+    /// Releases the deliveries made before `oldest_round`. Those that were not
+    /// acknowledged come back as sends to their recipient, the others, and
+    /// dummy deliveries, as padding.
+    pub fn expire(
+        &mut self,
+        oldest_round: u64,
+        pool: &ThreadPool,
+        num_threads: usize,
+    ) -> Vec<Record<N>> {
+        let mut num_expired = 0;
+        while let Some((round, count)) = self.history.front() {
+            if *round >= oldest_round {
+                break;
+            }
+            num_expired += count;
+            self.history.pop_front();
+        }
+
+        otils::compact(
+            &mut self.records[..],
+            |r| r.round < oldest_round,
+            pool,
+            num_threads,
+        );
+        self.records
+            .drain(0..num_expired)
+            .map(|r| {
+                let mut record = r.record;
+                let redeliver = record.is_send() && !r.acked;
+                record.rec_type = RecordType::from_u8(u8::oselect(
+                    redeliver,
+                    RecordType::Send as u8,
+                    RecordType::Dummy as u8,
+                ))
+                .unwrap();
+                record.padding = !redeliver;
+                record
+            })
+            .collect()
+    }
+
+    /// Appends the number of held deliveries and the deliveries themselves, as
+    /// written to a snapshot.
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&(self.records.len() as u64).to_le_bytes());
+        for r in self.records.iter() {
+            bytes.extend_from_slice(&r.round.to_le_bytes());
+            bytes.push(r.acked as u8);
+            r.record.encode(bytes);
+        }
+    }
+
+    pub fn decode(decoder: &mut Decoder) -> io::Result<Self> {
+        let mut pending = PendingDeliveries::new();
+        let num_records = decoder.u64()?;
+        for _ in 0..num_records {
+            let round = decoder.u64()?;
+            let acked = decoder.u8()? == 1;
+            let mut r = PendingRecord::delivery(Record::decode(decoder)?, round);
+            r.acked = acked;
+            pending.records.push(r);
+        }
+
+        let mut rounds: Vec<u64> = pending.records.iter().map(|r| r.round).collect();
+        rounds.sort();
+        for round in rounds {
+            match pending.history.back_mut() {
+                Some((last, count)) if *last == round => *count += 1,
+                _ => pending.history.push_back((round, 1)),
+            }
+        }
+        Ok(pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let mut pending: PendingDeliveries<4> = PendingDeliveries::new();
+        pending.hold(
+            &[
+                Record::send(1, [1; 4]),
+                Record::send(2, [2; 4]),
+                Record::new(2, RecordType::Dummy, 0, 0, 0),
+            ],
+            0,
+        );
+        pending.hold(&[Record::send(1, [3; 4])], 1);
+
+        pending.ack(&[(2, 0), (3, 0)], 4, &pool, 1);
+        assert_eq!(pending.len(), 4);
+
+        let expired = pending.expire(1, &pool, 1);
+        assert_eq!(expired.len(), 3);
+        let redelivered: Vec<(Uid, u8)> = expired
+            .iter()
+            .filter(|r| r.is_send())
+            .map(|r| (r.uid, r.message[0]))
+            .collect();
+        assert_eq!(redelivered, vec![(1, 1)]);
+        assert!(expired.iter().all(|r| r.is_send() != r.padding));
+
+        let mut bytes = Vec::new();
+        pending.encode(&mut bytes);
+        let mut restored = PendingDeliveries::<4>::decode(&mut Decoder::new(&bytes)).unwrap();
+        assert_eq!(restored.expire(2, &pool, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_ack_later_fetch() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let mut pending: PendingDeliveries<4> = PendingDeliveries::new();
+        // the delivery of the first fetch is lost, the second arrives
+        pending.hold(&[Record::send(1, [1; 4])], 0);
+        pending.hold(&[Record::send(1, [2; 4])], 1);
+
+        pending.ack(&[(1, 1)], 2, &pool, 1);
+        let redelivered: Vec<u8> = pending
+            .expire(2, &pool, 1)
+            .iter()
+            .filter(|r| r.is_send())
+            .map(|r| r.message[0])
+            .collect();
+        assert_eq!(redelivered, vec![1]);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::record::Uid;
+use chacha20poly1305::{
+    aead::{KeyInit, OsRng},
+    ChaCha20Poly1305,
+};
+
+pub const PRF_KEY_SIZE: usize = 32;
+
+/// Keyed PRF that maps a user's message counter to a submap index. The key is
+/// replaced every epoch so indices cannot be linked across rotations.
+#[derive(Clone)]
+pub struct Prf {
+    key: [u8; PRF_KEY_SIZE],
+    epoch: u64,
+}
+
+impl Prf {
+    pub fn new(key: [u8; PRF_KEY_SIZE], epoch: u64) -> Self {
+        Prf { key, epoch }
+    }
+
+    pub fn generate(epoch: u64) -> Self {
+        Prf::new(ChaCha20Poly1305::generate_key(&mut OsRng).into(), epoch)
+    }
+
+    pub fn key(&self) -> &[u8; PRF_KEY_SIZE] {
+        &self.key
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn rotate(&self) -> Self {
+        Prf::generate(self.epoch + 1)
+    }
+
+    pub fn eval(&self, uid: Uid, idx: u32) -> u32 {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        hasher.update(&uid.to_ne_bytes());
+        hasher.update(&idx.to_ne_bytes());
+        let hash = hasher.finalize();
+        u32::from_ne_bytes(<[u8; 4]>::try_from(&hash.as_bytes()[0..4]).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval() {
+        let prf = Prf::generate(0);
+        let indices: Vec<u32> = (0..8).map(|idx| prf.eval(1, idx)).collect();
+        assert_eq!(
+            indices,
+            (0..8).map(|idx| prf.eval(1, idx)).collect::<Vec<_>>()
+        );
+        assert_ne!(
+            indices,
+            (0..8).map(|idx| prf.eval(2, idx)).collect::<Vec<_>>()
+        );
+
+        let rotated = prf.rotate();
+        assert_eq!(rotated.epoch(), 1);
+        assert_ne!(rotated.key(), prf.key());
+        assert_ne!(
+            indices,
+            (0..8).map(|idx| rotated.eval(1, idx)).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use crate::codec::Decoder;
+use crate::envelope::{Envelope, NONCE_SIZE};
+use std::io::{self, Read, Write};
+
+/// Upper bound on a single frame, large enough for a fetch of a few thousand
+/// 4 KiB messages.
+pub const MAX_FRAME_SIZE: usize = 1 << 26;
+
+const SEND: u8 = 0;
+const FETCH: u8 = 1;
+const SENT: u8 = 2;
+const FETCHED: u8 = 3;
+const GROUP_SEND: u8 = 4;
+const ACK: u8 = 5;
+const ACKED: u8 = 6;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads one length-prefixed frame.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    read_frame_with_limit(reader, MAX_FRAME_SIZE)
+}
+
+/// Reads one length-prefixed frame of at most `max_size` bytes.
+pub fn read_frame_with_limit<R: Read>(reader: &mut R, max_size: usize) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > max_size {
+        return Err(invalid("frame too large"));
+    }
+
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+/// Writes one length-prefixed frame. Frames the reader would reject are not
+/// written.
+pub fn write_frame<W: Write>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    write_frame_with_limit(writer, frame, MAX_FRAME_SIZE)
+}
+
+/// Writes one length-prefixed frame of at most `max_size` bytes, which must
+/// fit the 32-bit length prefix.
+pub fn write_frame_with_limit<W: Write>(
+    writer: &mut W,
+    frame: &[u8],
+    max_size: usize,
+) -> io::Result<()> {
+    if frame.len() > max_size || frame.len() > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame too large",
+        ));
+    }
+    writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+    writer.write_all(frame)?;
+    writer.flush()
+}
+
+fn encode_envelope(envelope: &Envelope, bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&envelope.uid.to_le_bytes());
+    bytes.extend_from_slice(&envelope.nonce);
+    bytes.extend_from_slice(&(envelope.ciphertext.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&envelope.ciphertext);
+}
+
+fn decode_envelope(decoder: &mut Decoder) -> io::Result<Envelope> {
+    let uid = decoder.u128()?;
+    let nonce = decoder.bytes::<NONCE_SIZE>()?;
+    let len = decoder.u32()? as usize;
+    let ciphertext = decoder.take(len)?.to_vec();
+    Ok(Envelope {
+        uid,
+        nonce,
+        ciphertext,
+    })
+}
+
+#[derive(Debug)]
+pub enum Request {
+    Send(Envelope),
+    /// Fetch sealed under the fetching user's key.
+    Fetch(Envelope),
+    GroupSend(Envelope),
+    /// Acknowledgement, sealed under the user's key, of the deliveries of
+    /// one of the user's fetches.
+    Ack(Envelope),
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Request::Send(envelope) => {
+                bytes.push(SEND);
+                encode_envelope(envelope, &mut bytes);
+            }
+            Request::Fetch(envelope) => {
+                bytes.push(FETCH);
+                encode_envelope(envelope, &mut bytes);
+            }
+            Request::GroupSend(envelope) => {
+                bytes.push(GROUP_SEND);
+                encode_envelope(envelope, &mut bytes);
+            }
+            Request::Ack(envelope) => {
+                bytes.push(ACK);
+                encode_envelope(envelope, &mut bytes);
+            }
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut decoder = Decoder::new(bytes);
+        match decoder.u8()? {
+            SEND => Ok(Request::Send(decode_envelope(&mut decoder)?)),
+            FETCH => Ok(Request::Fetch(decode_envelope(&mut decoder)?)),
+            GROUP_SEND => Ok(Request::GroupSend(decode_envelope(&mut decoder)?)),
+            ACK => Ok(Request::Ack(decode_envelope(&mut decoder)?)),
+            _ => Err(invalid("unknown request")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Response {
+    Sent(bool),
+    Fetched(Vec<Envelope>),
+    Acked,
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Response::Sent(accepted) => {
+                bytes.push(SENT);
+                bytes.push(*accepted as u8);
+            }
+            Response::Fetched(envelopes) => {
+                bytes.push(FETCHED);
+                bytes.extend_from_slice(&(envelopes.len() as u32).to_le_bytes());
+                envelopes
+                    .iter()
+                    .for_each(|envelope| encode_envelope(envelope, &mut bytes));
+            }
+            Response::Acked => bytes.push(ACKED),
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut decoder = Decoder::new(bytes);
+        match decoder.u8()? {
+            SENT => Ok(Response::Sent(decoder.u8()? == 1)),
+            FETCHED => {
+                let len = decoder.u32()?;
+                let envelopes = (0..len)
+                    .map(|_| decode_envelope(&mut decoder))
+                    .collect::<io::Result<Vec<Envelope>>>()?;
+                Ok(Response::Fetched(envelopes))
+            }
+            ACKED => Ok(Response::Acked),
+            _ => Err(invalid("unknown response")),
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use blake3;
-use otils::Max;
-use std::cmp::Ordering;
+use crate::codec::Decoder;
+use crate::prf::Prf;
+use otils::{Max, ObliviousOps};
+use std::{cmp::Ordering, io};
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum RecordType {
@@ -10,11 +11,47 @@ pub enum RecordType {
     Dummy,
 }
 
-#[derive(Debug)]
-pub struct Record {
-    pub uid: i64,
+impl RecordType {
+    pub fn from_u8(rec_type: u8) -> Option<Self> {
+        match rec_type {
+            0 => Some(RecordType::User),
+            1 => Some(RecordType::Fetch),
+            2 => Some(RecordType::Send),
+            3 => Some(RecordType::Dummy),
+            _ => None,
+        }
+    }
+}
+
+/// Message width used by the benchmark, the payload size of the original
+/// experiments.
+pub const DEFAULT_MESSAGE_SIZE: usize = 96;
+
+/// Message carrying `x` in its leading bytes, as filled in by the benchmarks.
+pub fn message<const N: usize>(x: usize) -> [u8; N] {
+    let mut message = [0; N];
+    let bytes = x.to_ne_bytes();
+    let len = bytes.len().min(N);
+    message[..len].copy_from_slice(&bytes[..len]);
+    message
+}
+
+/// User identifier. Ids are arbitrary, e.g. hashes of public keys; every value
+/// is a valid id since padding is marked separately.
+pub type Uid = u128;
+
+/// Oblivious select on user ids, done on their 64-bit halves.
+pub fn select_uid(cond: bool, a: Uid, b: Uid) -> Uid {
+    let hi = u64::oselect(cond, (a >> 64) as u64, (b >> 64) as u64);
+    let lo = u64::oselect(cond, a as u64, b as u64);
+    ((hi as Uid) << 64) | lo as Uid
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record<const N: usize> {
+    pub uid: Uid,
     pub idx: u32,
-    pub map: u8,
+    pub map: u32,
 
     pub rec_type: RecordType,
     pub mark: u16,
@@ -23,11 +60,24 @@ pub struct Record {
     pub last_send: u32,
 
     pub data: u64,
-    pub _dum: [u64; 12],
+    pub padding: bool,
+
+    // round a message was stored in, which its retention counts from
+    pub round: u64,
+
+    // place of a send in the load balancer's batch; never leaves it, so it is
+    // not encoded
+    pub position: u64,
+
+    // authenticated sender of a send and its place in the sender's
+    // conversation with the recipient, zero for dummies
+    pub sender: Uid,
+    pub seq: u64,
+    pub message: [u8; N],
 }
 
-impl Record {
-    pub fn new(uid: i64, type_rec: RecordType, data: u64, map: u8, idx: u32) -> Self {
+impl<const N: usize> Record<N> {
+    pub fn new(uid: Uid, type_rec: RecordType, data: u64, map: u32, idx: u32) -> Self {
         Record {
             uid,
             idx,
@@ -37,15 +87,30 @@ impl Record {
             last_fetch: 0,
             last_send: 0,
             data,
-            _dum: [0; 12],
+            padding: false,
+            round: 0,
+            position: 0,
+            sender: 0,
+            seq: 0,
+            message: [0; N],
         }
     }
 
-    pub fn send(uid: i64, message: u64) -> Self {
-        Record::new(uid, RecordType::Send, message, 0, 0)
+    /// Record that does not belong to any user. Padding sorts after every
+    /// user's records and never matches a user's messages.
+    pub fn padding(rec_type: RecordType, data: u64, map: u32) -> Self {
+        let mut record = Record::new(Uid::MAX, rec_type, data, map, u32::MAX);
+        record.padding = true;
+        record
+    }
+
+    pub fn send(uid: Uid, message: [u8; N]) -> Self {
+        let mut record = Record::new(uid, RecordType::Send, 0, 0, 0);
+        record.message = message;
+        record
     }
 
-    pub fn fetch(uid: i64, volume: u64) -> Self {
+    pub fn fetch(uid: Uid, volume: u64) -> Self {
         Record::new(uid, RecordType::Fetch, volume, 0, 0)
     }
 
@@ -60,27 +125,68 @@ impl Record {
     pub fn is_send(&self) -> bool {
         self.rec_type == RecordType::Send
     }
+
+    /// Bytes `encode` writes for one record, as it travels to a submap and
+    /// is stored in a snapshot.
+    pub const ENCODED_SIZE: usize = 16 + 4 + 4 + 1 + 4 + 4 + 8 + 1 + 8 + 16 + 8 + N;
+
+    pub(crate) fn encode(&self, bytes: &mut Vec<u8>) {
+        let start = bytes.len();
+        bytes.extend_from_slice(&self.uid.to_le_bytes());
+        bytes.extend_from_slice(&self.idx.to_le_bytes());
+        bytes.extend_from_slice(&self.map.to_le_bytes());
+        bytes.push(self.rec_type.clone() as u8);
+        bytes.extend_from_slice(&self.last_fetch.to_le_bytes());
+        bytes.extend_from_slice(&self.last_send.to_le_bytes());
+        bytes.extend_from_slice(&self.data.to_le_bytes());
+        bytes.push(self.padding as u8);
+        bytes.extend_from_slice(&self.round.to_le_bytes());
+        bytes.extend_from_slice(&self.sender.to_le_bytes());
+        bytes.extend_from_slice(&self.seq.to_le_bytes());
+        bytes.extend_from_slice(&self.message);
+        debug_assert_eq!(bytes.len() - start, Self::ENCODED_SIZE);
+    }
+
+    pub(crate) fn decode(decoder: &mut Decoder) -> io::Result<Self> {
+        let uid = decoder.u128()?;
+        let idx = decoder.u32()?;
+        let map = decoder.u32()?;
+        let rec_type = RecordType::from_u8(decoder.u8()?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad record type"))?;
+
+        let mut record = Record::new(uid, rec_type, 0, map, idx);
+        record.last_fetch = decoder.u32()?;
+        record.last_send = decoder.u32()?;
+        record.data = decoder.u64()?;
+        record.padding = decoder.u8()? == 1;
+        record.round = decoder.u64()?;
+        record.sender = decoder.u128()?;
+        record.seq = decoder.u64()?;
+        record.message = decoder.bytes()?;
+        Ok(record)
+    }
 }
 
-pub struct IndexRecord(pub Record);
+#[derive(Clone)]
+pub(crate) struct IndexRecord<const N: usize>(pub Record<N>);
 
-impl IndexRecord {
-    pub fn new(uid: i64, rec_type: RecordType) -> Self {
+impl<const N: usize> IndexRecord<N> {
+    pub fn new(uid: Uid, rec_type: RecordType) -> Self {
         IndexRecord(Record::new(uid, rec_type, 0, 0, 0))
     }
 
     pub fn dummy_fetches(&self) -> Vec<Self> {
         (0..self.0.data)
-            .map(|_| IndexRecord::new(self.0.uid, RecordType::Fetch))
+            .map(|_| {
+                let mut fetch = IndexRecord::new(self.0.uid, RecordType::Fetch);
+                fetch.0.padding = self.0.padding;
+                fetch
+            })
             .collect()
     }
 
-    pub fn get_idx(&self, idx: u32) -> u32 {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(&self.0.uid.to_ne_bytes());
-        hasher.update(&idx.to_ne_bytes());
-        let hash = hasher.finalize();
-        u32::from_ne_bytes(<[u8; 4]>::try_from(&hash.as_bytes()[0..4]).unwrap())
+    pub fn get_idx(&self, prf: &Prf, idx: u32) -> u32 {
+        prf.eval(self.0.uid, idx)
     }
 
     pub fn is_request(&self) -> bool {
@@ -88,60 +194,69 @@ impl IndexRecord {
     }
 
     pub fn is_updated_user_store(&self) -> bool {
-        self.0.mark == 1 && self.0.uid != i64::MAX
+        self.0.mark == 1
     }
 
-    pub fn set_user_store(&mut self) {
-        self.0.rec_type = RecordType::User;
+    pub fn user_store_entry(&self) -> Self {
+        let mut record = IndexRecord::new(self.0.uid, RecordType::User);
+        record.0.padding = self.0.padding;
+        record.0.mark = self.0.mark;
+        record.0.last_fetch = self.0.last_fetch;
+        record.0.last_send = self.0.last_send;
+        record
     }
 }
 
-impl PartialEq for IndexRecord {
+impl<const N: usize> PartialEq for IndexRecord<N> {
     fn eq(&self, other: &Self) -> bool {
-        self.0.uid == other.0.uid && self.0.rec_type == other.0.rec_type
+        self.0.padding == other.0.padding
+            && self.0.uid == other.0.uid
+            && self.0.rec_type == other.0.rec_type
     }
 }
 
-impl PartialOrd for IndexRecord {
+impl<const N: usize> PartialOrd for IndexRecord<N> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let padding_ord = self.0.padding.partial_cmp(&other.0.padding);
         let uid_ord = self.0.uid.partial_cmp(&other.0.uid);
         let type_ord = self.0.rec_type.partial_cmp(&other.0.rec_type);
-        match uid_ord {
-            Some(Ordering::Equal) => type_ord,
-            x => x,
+        match (padding_ord, uid_ord) {
+            (Some(Ordering::Equal), Some(Ordering::Equal)) => type_ord,
+            (Some(Ordering::Equal), x) => x,
+            (x, _) => x,
         }
     }
 }
 
-impl Max for IndexRecord {
+impl<const N: usize> Max for IndexRecord<N> {
     fn maximum() -> Self {
-        IndexRecord(Record::new(i64::MAX, RecordType::Dummy, 0, 0, 0))
+        IndexRecord(Record::padding(RecordType::Dummy, 0, 0))
     }
 }
 
-pub struct SubmapRecord(pub Record);
+pub(crate) struct SubmapRecord<const N: usize>(pub Record<N>);
 
-impl SubmapRecord {
-    pub fn dummy_send(num_requests: usize, map: u8) -> Vec<Self> {
+impl<const N: usize> SubmapRecord<N> {
+    pub fn dummy_send(num_requests: usize, map: u32) -> Vec<Self> {
         (0..num_requests)
-            .map(|_| SubmapRecord(Record::new(0, RecordType::Dummy, 0, map, u32::MAX)))
+            .map(|_| SubmapRecord(Record::padding(RecordType::Dummy, 0, map)))
             .collect()
     }
 
-    pub fn dummy_fetch(num_requests: usize, map: u8) -> Vec<Self> {
+    pub fn dummy_fetch(num_requests: usize, map: u32) -> Vec<Self> {
         (0..num_requests)
-            .map(|_| SubmapRecord(Record::new(0, RecordType::Fetch, 0, map, u32::MAX)))
+            .map(|_| SubmapRecord(Record::padding(RecordType::Fetch, 0, map)))
             .collect()
     }
 }
 
-impl PartialEq for SubmapRecord {
+impl<const N: usize> PartialEq for SubmapRecord<N> {
     fn eq(&self, other: &Self) -> bool {
         self.0.map == other.0.map && self.0.rec_type == other.0.rec_type
     }
 }
 
-impl PartialOrd for SubmapRecord {
+impl<const N: usize> PartialOrd for SubmapRecord<N> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let map_ord = self.0.map.partial_cmp(&other.0.map);
         let idx_ord = self.0.idx.partial_cmp(&other.0.idx);
@@ -152,8 +267,8 @@ impl PartialOrd for SubmapRecord {
     }
 }
 
-impl Max for SubmapRecord {
+impl<const N: usize> Max for SubmapRecord<N> {
     fn maximum() -> Self {
-        SubmapRecord(Record::new(0, RecordType::Dummy, 0, u8::MAX, 0))
+        SubmapRecord(Record::new(0, RecordType::Dummy, 0, u32::MAX, 0))
     }
 }
@@ -0,0 +1,424 @@
+use sparta::{Envelope, LoadBalancer, Record, RecordType, Request, Response, Result, Uid};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+pub type Ticket = u64;
+
+/// Public shape of every round: how often it closes and how many sends, group
+/// sends, fetched messages and acknowledgements it carries once padded. Group
+/// sends are refused when `group_slots` is zero; acknowledgements are answered
+/// without a batch when `ack_slots` is zero, for load balancers whose
+/// deliveries are final. Expired messages are collected every `gc_interval`
+/// rounds and the PRF key is rotated every `rotate_interval` rounds, either
+/// never if zero.
+#[derive(Clone, Debug)]
+pub struct RoundConfig {
+    pub interval: Duration,
+    pub send_slots: usize,
+    pub fetch_slots: usize,
+    pub group_slots: usize,
+    pub ack_slots: usize,
+    pub gc_interval: u64,
+    pub rotate_interval: u64,
+}
+
+/// Groups requests into rounds around a load balancer. A round closes when its
+/// timer expires or enough requests are queued to fill it, and is always padded
+/// to the configured number of slots, so batch sizes only reveal the schedule.
+/// Each user gets at most one fetch per round; further fetches wait for a
+/// later round. A fetch must authenticate under its user's key and carry a
+/// larger stamp than the user's last fetch, or it is answered with no
+/// deliveries. An acknowledgement must authenticate under its user's key and
+/// name the stamp of a fetch whose deliveries are still held, or it has no
+/// effect.
+pub struct RoundScheduler<const N: usize> {
+    lb: LoadBalancer<N>,
+    config: RoundConfig,
+    opened: Instant,
+    next_ticket: Ticket,
+    sends: VecDeque<(Ticket, Envelope)>,
+    group_sends: VecDeque<(Ticket, Envelope)>,
+    fetches: VecDeque<(Ticket, Uid, usize, u64)>,
+    fetch_volume: usize,
+    // stamp of every user's last accepted fetch
+    fetch_stamps: HashMap<Uid, u64>,
+    // round of every fetch, by user and stamp, whose deliveries may be held
+    fetch_rounds: HashMap<(Uid, u64), u64>,
+    acks: VecDeque<(Ticket, Uid, u64)>,
+    // responses to batches of a round that failed later on
+    responses: Vec<(Ticket, Response)>,
+}
+
+impl<const N: usize> RoundScheduler<N> {
+    pub fn new(lb: LoadBalancer<N>, config: RoundConfig) -> Self {
+        RoundScheduler {
+            lb,
+            config,
+            opened: Instant::now(),
+            next_ticket: 0,
+            sends: VecDeque::new(),
+            group_sends: VecDeque::new(),
+            fetches: VecDeque::new(),
+            fetch_volume: 0,
+            fetch_stamps: HashMap::new(),
+            fetch_rounds: HashMap::new(),
+            acks: VecDeque::new(),
+            responses: Vec::new(),
+        }
+    }
+
+    /// Queues a request for the next round that has room for it.
+    pub fn submit(&mut self, request: Request) -> Ticket {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+
+        match request {
+            Request::Send(envelope) => self.sends.push_back((ticket, envelope)),
+            Request::GroupSend(envelope) => self.group_sends.push_back((ticket, envelope)),
+            Request::Fetch(envelope) => match self.open_fetch(&envelope) {
+                Some((stamp, volume)) => {
+                    let volume = (volume as usize).min(self.config.fetch_slots);
+                    self.fetches
+                        .push_back((ticket, envelope.uid, volume, stamp));
+                    self.fetch_volume += volume;
+                }
+                None => self.responses.push((ticket, Response::Fetched(Vec::new()))),
+            },
+            Request::Ack(envelope) => match self.open_ack(&envelope) {
+                Some(round) => self.acks.push_back((ticket, envelope.uid, round)),
+                None => self.responses.push((ticket, Response::Acked)),
+            },
+        }
+        ticket
+    }
+
+    /// Stamp and volume of a fetch that authenticates and is newer than the
+    /// last fetch of its user.
+    fn open_fetch(&mut self, envelope: &Envelope) -> Option<(u64, u64)> {
+        let (stamp, volume) = self.lb.open_fetch(envelope)?;
+        let last = self.fetch_stamps.entry(envelope.uid).or_insert(0);
+        if stamp <= *last {
+            return None;
+        }
+        *last = stamp;
+        Some((stamp, volume))
+    }
+
+    /// Round of the fetch named by an acknowledgement that authenticates.
+    fn open_ack(&self, envelope: &Envelope) -> Option<u64> {
+        let stamp = self.lb.open_ack(envelope)?;
+        self.fetch_rounds.get(&(envelope.uid, stamp)).copied()
+    }
+
+    pub fn time_left(&self) -> Duration {
+        self.config.interval.saturating_sub(self.opened.elapsed())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.time_left().is_zero()
+            || self.sends.len() >= self.config.send_slots
+            || (!self.group_sends.is_empty() && self.group_sends.len() >= self.config.group_slots)
+            || self.fetch_volume >= self.config.fetch_slots
+            || (!self.acks.is_empty() && self.acks.len() >= self.config.ack_slots)
+    }
+
+    fn next_fetches(&mut self) -> Vec<(Ticket, Uid, usize, u64)> {
+        let mut fetches = Vec::new();
+        let mut fetched = HashSet::new();
+        let mut deferred = VecDeque::new();
+        let mut remaining = self.config.fetch_slots;
+
+        while let Some(fetch) = self.fetches.pop_front() {
+            let (_, uid, volume, _) = fetch;
+            if volume > remaining {
+                self.fetches.push_front(fetch);
+                break;
+            }
+            if !fetched.insert(uid) {
+                deferred.push_back(fetch);
+                continue;
+            }
+            remaining -= volume;
+            self.fetch_volume -= volume;
+            fetches.push(fetch);
+        }
+
+        deferred.extend(self.fetches.drain(..));
+        self.fetches = deferred;
+        fetches
+    }
+
+    /// Runs the next batch of sends, or of group sends, padded to its slots.
+    /// Sends leave their queue only once the batch has been stored.
+    fn run_sends(&mut self, group: bool) -> Result<()> {
+        let (queue, slots) = match group {
+            false => (&self.sends, self.config.send_slots),
+            true => (&self.group_sends, self.config.group_slots),
+        };
+        let num_sends = queue.len().min(slots);
+        let mut sends: Vec<Envelope> = queue
+            .iter()
+            .take(num_sends)
+            .map(|(_, envelope)| envelope.clone())
+            .collect();
+        sends.resize_with(slots, Envelope::dummy);
+
+        let accepted = match group {
+            false => self.lb.batch_send(sends)?,
+            true => self.lb.batch_group_send(sends)?,
+        };
+        let queue = match group {
+            false => &mut self.sends,
+            true => &mut self.group_sends,
+        };
+        self.responses.extend(
+            queue
+                .drain(..num_sends)
+                .zip(accepted)
+                .map(|((ticket, _), accepted)| (ticket, Response::Sent(accepted))),
+        );
+        Ok(())
+    }
+
+    fn run_acks(&mut self) -> Result<()> {
+        let num_acks = self.acks.len().min(self.config.ack_slots);
+        let acks = self
+            .acks
+            .iter()
+            .take(num_acks)
+            .map(|(_, uid, round)| (*uid, *round))
+            .collect();
+
+        self.lb.batch_ack(acks, self.config.ack_slots)?;
+        self.responses.extend(
+            self.acks
+                .drain(..num_acks)
+                .map(|(ticket, _, _)| (ticket, Response::Acked)),
+        );
+        Ok(())
+    }
+
+    fn run_fetches(&mut self) -> Result<()> {
+        let fetches = self.next_fetches();
+        let mut requests: Vec<Record<N>> = fetches
+            .iter()
+            .map(|(_, uid, volume, _)| Record::fetch(*uid, *volume as u64))
+            .collect();
+        let volume = fetches
+            .iter()
+            .fold(0, |acc, (_, _, volume, _)| acc + volume);
+        requests.push(Record::padding(
+            RecordType::Fetch,
+            (self.config.fetch_slots - volume) as u64,
+            0,
+        ));
+
+        let delivered = match self.lb.batch_fetch(requests) {
+            Ok(delivered) => delivered,
+            Err(e) => {
+                for fetch in fetches.into_iter().rev() {
+                    self.fetch_volume += fetch.2;
+                    self.fetches.push_front(fetch);
+                }
+                return Err(e);
+            }
+        };
+
+        if self.lb.ack_timeout.is_some() {
+            let round = self.lb.round();
+            self.fetch_rounds.extend(
+                fetches
+                    .iter()
+                    .map(|(_, uid, _, stamp)| ((*uid, *stamp), round)),
+            );
+        }
+        let mut deliveries: HashMap<Uid, Vec<Envelope>> = HashMap::new();
+        for envelope in delivered {
+            deliveries.entry(envelope.uid).or_default().push(envelope);
+        }
+        self.responses
+            .extend(fetches.into_iter().map(|(ticket, uid, _, _)| {
+                let envelopes = deliveries.remove(&uid).unwrap_or_default();
+                (ticket, Response::Fetched(envelopes))
+            }));
+        Ok(())
+    }
+
+    /// Closes the current round and runs it against the load balancer, returning
+    /// the response to every request it served. If a batch fails, the load
+    /// balancer rolls it back and its requests stay queued for the next round,
+    /// which also returns the responses to the batches that did run.
+    pub fn run_round(&mut self) -> Result<Vec<(Ticket, Response)>> {
+        self.opened = Instant::now();
+
+        self.run_sends(false)?;
+        if self.config.group_slots == 0 {
+            self.responses.extend(
+                self.group_sends
+                    .drain(..)
+                    .map(|(ticket, _)| (ticket, Response::Sent(false))),
+            );
+        } else {
+            self.run_sends(true)?;
+        }
+        // acknowledgements only cover deliveries of earlier rounds
+        if self.config.ack_slots == 0 {
+            self.responses.extend(
+                self.acks
+                    .drain(..)
+                    .map(|(ticket, _, _)| (ticket, Response::Acked)),
+            );
+        } else {
+            self.run_acks()?;
+        }
+        self.run_fetches()?;
+        self.lb.redeliver()?;
+        // forget fetches whose deliveries are no longer held
+        let oldest_round = self.lb.ack_timeout.map_or(u64::MAX, |timeout| {
+            (self.lb.round() + 1).saturating_sub(timeout)
+        });
+        self.fetch_rounds.retain(|_, round| *round >= oldest_round);
+
+        self.lb.next_round();
+        if self.config.gc_interval > 0 && self.lb.round().is_multiple_of(self.config.gc_interval) {
+            self.lb.collect_garbage()?;
+        }
+        if self.config.rotate_interval > 0
+            && self.lb.round().is_multiple_of(self.config.rotate_interval)
+        {
+            self.lb.rotate_prf()?;
+        }
+
+        Ok(std::mem::take(&mut self.responses))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sparta::UserKeys;
+
+    #[test]
+    fn test_one_fetch_per_user() {
+        let keys = UserKeys::generate();
+        let lb: LoadBalancer<16> = LoadBalancer::new(4, 6, 2, keys.clone()).unwrap();
+        let config = RoundConfig {
+            interval: Duration::from_secs(60),
+            send_slots: 4,
+            fetch_slots: 8,
+            group_slots: 0,
+            ack_slots: 0,
+            gc_interval: 0,
+            rotate_interval: 0,
+        };
+        let mut scheduler = RoundScheduler::new(lb, config);
+
+        let fetch = |uid: Uid, stamp: u64| {
+            Request::Fetch(Envelope::seal_fetch(&keys.key(uid), uid, stamp, 2))
+        };
+        let send = Envelope::seal_send(&keys.key(0), 0, 1, 1, &[1; 16]);
+        let sent = scheduler.submit(Request::Send(send));
+        let first = scheduler.submit(fetch(1, 1));
+        let second = scheduler.submit(fetch(1, 2));
+        let other = scheduler.submit(fetch(2, 1));
+        assert!(!scheduler.is_ready());
+
+        let responses = scheduler.run_round().unwrap();
+        let tickets: Vec<Ticket> = responses.iter().map(|(ticket, _)| *ticket).collect();
+        assert_eq!(tickets, vec![sent, first, other]);
+        for (_, response) in responses.iter().skip(1) {
+            assert!(matches!(response, Response::Fetched(envelopes) if envelopes.len() == 2));
+        }
+
+        let responses = scheduler.run_round().unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].0, second);
+    }
+
+    #[test]
+    fn test_fetch_auth() {
+        let keys = UserKeys::generate();
+        let lb: LoadBalancer<16> = LoadBalancer::new(4, 6, 2, keys.clone()).unwrap();
+        let config = RoundConfig {
+            interval: Duration::from_secs(60),
+            send_slots: 4,
+            fetch_slots: 8,
+            group_slots: 0,
+            ack_slots: 0,
+            gc_interval: 0,
+            rotate_interval: 0,
+        };
+        let mut scheduler = RoundScheduler::new(lb, config);
+
+        let send = Envelope::seal_send(&keys.key(0), 0, 1, 1, &[1; 16]);
+        scheduler.submit(Request::Send(send));
+        let fetch = Envelope::seal_fetch(&keys.key(1), 1, 5, 1);
+        let mut forged = Envelope::seal_fetch(&keys.key(2), 2, 5, 1);
+        forged.uid = 1;
+        let forged = scheduler.submit(Request::Fetch(forged));
+        let fetched = scheduler.submit(Request::Fetch(fetch.clone()));
+        let replayed = scheduler.submit(Request::Fetch(fetch));
+
+        let responses: HashMap<Ticket, Response> =
+            scheduler.run_round().unwrap().into_iter().collect();
+        let deliveries = |ticket| match &responses[&ticket] {
+            Response::Fetched(envelopes) => envelopes.len(),
+            response => panic!("unexpected response {:?}", response),
+        };
+        assert_eq!(deliveries(forged), 0);
+        assert_eq!(deliveries(replayed), 0);
+        assert_eq!(deliveries(fetched), 1);
+    }
+
+    #[test]
+    fn test_ack() {
+        let keys = UserKeys::generate();
+        let mut lb: LoadBalancer<16> = LoadBalancer::new(4, 6, 2, keys.clone()).unwrap();
+        lb.ack_timeout = Some(2);
+        let config = RoundConfig {
+            interval: Duration::from_secs(60),
+            send_slots: 1,
+            fetch_slots: 1,
+            group_slots: 0,
+            ack_slots: 2,
+            gc_interval: 0,
+            rotate_interval: 0,
+        };
+        let mut scheduler = RoundScheduler::new(lb, config);
+
+        let fetch = |scheduler: &mut RoundScheduler<16>, stamp: u64| -> Vec<u8> {
+            let fetch = Envelope::seal_fetch(&keys.key(1), 1, stamp, 1);
+            let ticket = scheduler.submit(Request::Fetch(fetch));
+            let responses: HashMap<Ticket, Response> =
+                scheduler.run_round().unwrap().into_iter().collect();
+            let Response::Fetched(envelopes) = &responses[&ticket] else {
+                panic!("expected deliveries");
+            };
+            envelopes
+                .iter()
+                .map(|envelope| envelope.open_delivery::<16>(&keys.key(1)).unwrap())
+                .filter(|record| record.is_send())
+                .map(|record| record.message[0])
+                .collect()
+        };
+        let send =
+            |x: u8| Request::Send(Envelope::seal_send(&keys.key(0), 0, 1, x as u64, &[x; 16]));
+
+        // the delivery of the first fetch is lost, that of the second arrives
+        scheduler.submit(send(1));
+        assert_eq!(fetch(&mut scheduler, 1), vec![1]);
+        scheduler.submit(send(2));
+        assert_eq!(fetch(&mut scheduler, 2), vec![2]);
+
+        // acknowledging the second fetch, and a forged acknowledgement of the
+        // first, leave the lost delivery to be delivered again
+        scheduler.submit(Request::Ack(Envelope::seal_ack(&keys.key(1), 1, 2)));
+        let mut forged = Envelope::seal_ack(&keys.key(2), 2, 1);
+        forged.uid = 1;
+        scheduler.submit(Request::Ack(forged));
+        assert_eq!(fetch(&mut scheduler, 3), Vec::<u8>::new());
+        assert_eq!(fetch(&mut scheduler, 4), vec![1]);
+    }
+}
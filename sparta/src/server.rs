@@ -0,0 +1,235 @@
+use crate::scheduler::{RoundConfig, RoundScheduler, Ticket};
+use sparta::{LoadBalancer, Request, Response, Result, SpartaError};
+use std::{
+    collections::HashMap,
+    io,
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+type Pending = Vec<(Request, Sender<Response>)>;
+
+/// Rounds in a row that may fail, each retried by the next, before the server
+/// gives up.
+const MAX_FAILED_ROUNDS: usize = 3;
+
+/// Accepts send and fetch requests from many clients over TCP and serves them
+/// in rounds closed by a round scheduler, routing each response back to the
+/// connection that asked for it.
+pub struct Server<const N: usize> {
+    scheduler: RoundScheduler<N>,
+    pending: Arc<Mutex<Pending>>,
+    replies: HashMap<Ticket, Sender<Response>>,
+}
+
+impl<const N: usize> Server<N> {
+    pub fn new(lb: LoadBalancer<N>, config: RoundConfig) -> Self {
+        Server {
+            scheduler: RoundScheduler::new(lb, config),
+            pending: Arc::new(Mutex::new(Vec::new())),
+            replies: HashMap::new(),
+        }
+    }
+
+    /// Serves requests until `MAX_FAILED_ROUNDS` rounds in a row fail, or a
+    /// round fails in a way that retrying cannot fix, and returns the last
+    /// error. Open connections are closed without a response then.
+    pub fn run(mut self, listener: TcpListener) -> Result<()> {
+        let pending = Arc::clone(&self.pending);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let pending = Arc::clone(&pending);
+                thread::spawn(move || handle(stream, pending));
+            }
+        });
+
+        let mut failed_rounds = 0;
+        loop {
+            let requests = std::mem::take(&mut *self.pending.lock().unwrap());
+            for (request, reply) in requests {
+                let ticket = self.scheduler.submit(request);
+                self.replies.insert(ticket, reply);
+            }
+
+            if self.scheduler.is_ready() {
+                // a failed round is rolled back and its requests retried next round
+                match self.run_round() {
+                    Ok(()) => failed_rounds = 0,
+                    Err(e @ SpartaError::CommitFailed { .. }) => return Err(e),
+                    Err(e) => {
+                        failed_rounds += 1;
+                        if failed_rounds == MAX_FAILED_ROUNDS {
+                            return Err(e);
+                        }
+                        eprintln!("round failed: {}", e);
+                    }
+                }
+            } else {
+                thread::sleep(self.scheduler.time_left().min(Duration::from_millis(1)));
+            }
+        }
+    }
+
+    pub fn run_round(&mut self) -> Result<()> {
+        for (ticket, response) in self.scheduler.run_round()? {
+            if let Some(reply) = self.replies.remove(&ticket) {
+                let _ = reply.send(response);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn handle(mut stream: TcpStream, pending: Arc<Mutex<Pending>>) -> io::Result<()> {
+    loop {
+        let request = Request::decode(&sparta::read_frame(&mut stream)?)?;
+
+        let (reply, response) = mpsc::channel();
+        pending.lock().unwrap().push((request, reply));
+        let response = response
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "server stopped"))?;
+
+        sparta::write_frame(&mut stream, &response.encode())?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sparta::{Envelope, Record, Submap, Uid, UserKeys};
+    use std::sync::Barrier;
+
+    const NUM_CLIENTS: Uid = 4;
+
+    // submap whose machine is gone
+    struct LostSubmap;
+
+    fn lost<T>() -> Result<T> {
+        Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+    }
+
+    impl Submap<16> for LostSubmap {
+        fn batch_send(&mut self, _: Vec<Record<16>>, _: u64) -> Result<()> {
+            lost()
+        }
+
+        fn batch_fetch(&mut self, _: Vec<Record<16>>) -> Result<Vec<Record<16>>> {
+            lost()
+        }
+
+        fn collect_garbage(&mut self, _: u64) -> Result<()> {
+            lost()
+        }
+
+        fn drain(&mut self) -> Result<Vec<Record<16>>> {
+            lost()
+        }
+
+        fn encode(&self, _: &mut Vec<u8>) -> Result<()> {
+            lost()
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            lost()
+        }
+
+        fn rollback(&mut self) -> Result<()> {
+            lost()
+        }
+    }
+
+    fn call(stream: &mut TcpStream, request: Request) -> Response {
+        sparta::write_frame(stream, &request.encode()).unwrap();
+        Response::decode(&sparta::read_frame(stream).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_loopback() {
+        let keys = UserKeys::generate();
+        let lb: LoadBalancer<16> =
+            LoadBalancer::new(NUM_CLIENTS as usize, 6, 2, keys.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = RoundConfig {
+            interval: Duration::from_millis(20),
+            send_slots: 8,
+            fetch_slots: 16,
+            group_slots: 0,
+            ack_slots: 0,
+            gc_interval: 0,
+            rotate_interval: 0,
+        };
+        thread::spawn(move || Server::new(lb, config).run(listener));
+
+        let barrier = Arc::new(Barrier::new(NUM_CLIENTS as usize));
+        let clients: Vec<_> = (0..NUM_CLIENTS)
+            .map(|uid| {
+                let key = keys.key(uid);
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    let recipient = (uid + 1) % NUM_CLIENTS;
+                    for i in 0..2 {
+                        let envelope = Envelope::seal_send(
+                            &key,
+                            uid,
+                            recipient,
+                            i as u64 + 1,
+                            &[uid as u8 + i; 16],
+                        );
+                        let response = call(&mut stream, Request::Send(envelope));
+                        assert!(matches!(response, Response::Sent(true)));
+                    }
+                    barrier.wait();
+
+                    let fetch = Envelope::seal_fetch(&key, uid, 1, 3);
+                    let Response::Fetched(envelopes) = call(&mut stream, Request::Fetch(fetch))
+                    else {
+                        panic!("expected deliveries");
+                    };
+                    let mut messages: Vec<u8> = envelopes
+                        .iter()
+                        .map(|envelope| envelope.open_delivery::<16>(&key).unwrap())
+                        .filter(|record| record.is_send())
+                        .map(|record| record.message[0])
+                        .collect();
+                    messages.sort();
+
+                    let sender = ((uid + NUM_CLIENTS - 1) % NUM_CLIENTS) as u8;
+                    assert_eq!(envelopes.len(), 3);
+                    assert_eq!(messages, vec![sender, sender + 1]);
+                })
+            })
+            .collect();
+
+        for client in clients {
+            client.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_failed_rounds() {
+        let submaps: Vec<Box<dyn Submap<16>>> = vec![Box::new(LostSubmap)];
+        let lb = LoadBalancer::with_submaps(4, 2, submaps, UserKeys::generate()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = RoundConfig {
+            interval: Duration::from_millis(1),
+            send_slots: 1,
+            fetch_slots: 1,
+            group_slots: 0,
+            ack_slots: 0,
+            gc_interval: 0,
+            rotate_interval: 0,
+        };
+        assert!(matches!(
+            Server::new(lb, config).run(listener),
+            Err(SpartaError::Io(_))
+        ));
+    }
+}
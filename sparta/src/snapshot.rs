@@ -0,0 +1,99 @@
+use crate::codec::Decoder;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+pub const SEALING_KEY_SIZE: usize = 32;
+
+const MAGIC: &[u8; 8] = b"SPARTA\x00\x04";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn aad(counter: u64) -> [u8; 16] {
+    let mut aad = [0; 16];
+    aad[..8].copy_from_slice(MAGIC);
+    aad[8..].copy_from_slice(&counter.to_le_bytes());
+    aad
+}
+
+/// Encrypts `body` under the sealing key and writes it to `path`. The
+/// rollback counter is bound to the ciphertext, so a snapshot only restores
+/// against the counter value it was taken with.
+pub fn seal(
+    path: &Path,
+    key: &[u8; SEALING_KEY_SIZE],
+    counter: u64,
+    body: &[u8],
+) -> io::Result<()> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: body,
+        aad: &aad(counter),
+    };
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| invalid("snapshot: encryption failed"))?;
+
+    // write to a temporary file first so a crash never leaves a torn snapshot
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(MAGIC)?;
+    file.write_all(&counter.to_le_bytes())?;
+    file.write_all(&nonce)?;
+    file.write_all(&ciphertext)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+/// Reads and authenticates the snapshot at `path`, rejecting it unless it was
+/// sealed with `counter`.
+pub fn open(path: &Path, key: &[u8; SEALING_KEY_SIZE], counter: u64) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+
+    let mut decoder = Decoder::new(&contents);
+    if decoder.bytes::<8>()? != *MAGIC {
+        return Err(invalid("snapshot: bad magic"));
+    }
+    if decoder.u64()? != counter {
+        return Err(invalid("snapshot: rollback counter mismatch"));
+    }
+    let nonce = decoder.bytes::<12>()?;
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let payload = Payload {
+        msg: decoder.rest(),
+        aad: &aad(counter),
+    };
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| invalid("snapshot: authentication failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let path =
+            std::env::temp_dir().join(format!("sparta-snapshot-test-{}", std::process::id()));
+        let key = [1; SEALING_KEY_SIZE];
+        seal(&path, &key, 7, b"user store").unwrap();
+
+        assert_eq!(open(&path, &key, 7).unwrap(), b"user store");
+        assert!(open(&path, &key, 6).is_err());
+        assert!(open(&path, &[2; SEALING_KEY_SIZE], 7).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+}
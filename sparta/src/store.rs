@@ -0,0 +1,175 @@
+use crate::envelope::UserKeys;
+use crate::error::{Result, SpartaError};
+use crate::load_balancer::{LoadBalancer, Schedule};
+use crate::omq::ObliviousMultiQueue;
+use crate::record::Record;
+use std::{fmt, str::FromStr};
+
+/// Largest volume of a single fetch, the largest the benchmarks use. A fetch
+/// expands into one request per message before the batch is padded, so its
+/// volume bounds the memory a single request can claim.
+pub const MAX_FETCH_VOLUME: u64 = 1 << 20;
+
+/// Batched message storage. Sends are records addressed to their recipient's
+/// uid and fetches are records whose `data` holds the number of messages to
+/// return.
+pub trait MessageStore<const N: usize> {
+    /// Stores a batch of sends. Returns, in request order, whether each send
+    /// was stored.
+    fn batch_send(&mut self, sends: Vec<Record<N>>) -> Result<Vec<bool>>;
+
+    /// Answers every fetch with exactly `volume` records grouped by recipient,
+    /// dummies filling in for missing messages.
+    fn batch_fetch(&mut self, fetches: Vec<Record<N>>) -> Result<Vec<Record<N>>>;
+}
+
+pub(crate) fn validate_sends<const N: usize>(sends: &[Record<N>]) -> Result<()> {
+    if !sends.iter().all(|send| send.is_send()) {
+        return Err(SpartaError::InvalidRequest("batch_send takes send records"));
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_fetches<const N: usize>(fetches: &[Record<N>]) -> Result<()> {
+    if !fetches.iter().all(|fetch| fetch.is_fetch()) {
+        return Err(SpartaError::InvalidRequest(
+            "batch_fetch takes fetch records",
+        ));
+    }
+    if fetches.iter().any(|fetch| fetch.data > MAX_FETCH_VOLUME) {
+        return Err(SpartaError::InvalidRequest("fetch volume too large"));
+    }
+    Ok(())
+}
+
+/// Implementation behind a `MessageStore`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Load balancer whose submaps all run at once, sharing the threads.
+    Parallel,
+    /// Load balancer whose submaps run one after another on all threads, as
+    /// each would on its own machine in a distributed deployment.
+    Sequential,
+    /// Single oblivious multiqueue without a load balancer.
+    SingleQueue,
+}
+
+impl Backend {
+    /// Opens an empty store. The load balancers start with users `0..num_users`
+    /// and spread messages over `num_submaps` submaps; the single queue ignores
+    /// both.
+    pub fn open<const N: usize>(
+        self,
+        num_users: usize,
+        num_threads: usize,
+        num_submaps: usize,
+    ) -> Result<Box<dyn MessageStore<N>>> {
+        let keys = UserKeys::generate();
+        Ok(
+            match self.open_load_balancer(num_users, num_threads, num_submaps, keys)? {
+                Some(l) => Box::new(l),
+                None => Box::new(ObliviousMultiQueue::new(num_threads)?),
+            },
+        )
+    }
+
+    /// Opens an empty load balancer deriving its users' keys from `keys`, or
+    /// `None` for the single queue, which has no load balancer.
+    pub fn open_load_balancer<const N: usize>(
+        self,
+        num_users: usize,
+        num_threads: usize,
+        num_submaps: usize,
+        keys: UserKeys,
+    ) -> Result<Option<LoadBalancer<N>>> {
+        let schedule = match self {
+            Backend::Parallel => Schedule::Parallel,
+            Backend::Sequential => Schedule::Sequential,
+            Backend::SingleQueue => return Ok(None),
+        };
+        LoadBalancer::with_schedule(num_users, num_threads, num_submaps, keys, schedule).map(Some)
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "parallel" => Ok(Backend::Parallel),
+            "sequential" => Ok(Backend::Sequential),
+            "single-queue" => Ok(Backend::SingleQueue),
+            _ => Err(format!("unknown backend: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Backend::Parallel => "parallel",
+            Backend::Sequential => "sequential",
+            Backend::SingleQueue => "single-queue",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load_balancer::MAX_SUBMAPS;
+
+    #[test]
+    fn test_backends() {
+        for backend in [Backend::Parallel, Backend::Sequential, Backend::SingleQueue] {
+            let mut store = backend.open::<4>(4, 6, 2).unwrap();
+
+            let sends = vec![
+                Record::send(1, [1; 4]),
+                Record::send(2, [2; 4]),
+                Record::send(1, [3; 4]),
+            ];
+            assert_eq!(store.batch_send(sends).unwrap(), vec![true; 3]);
+
+            let responses = store
+                .batch_fetch(vec![Record::fetch(1, 3), Record::fetch(2, 1)])
+                .unwrap();
+            assert_eq!(responses.len(), 4, "{}", backend);
+
+            let mut delivered: Vec<(u128, u8)> = responses
+                .iter()
+                .filter(|r| r.is_send())
+                .map(|r| (r.uid, r.message[0]))
+                .collect();
+            delivered.sort();
+            assert_eq!(delivered, vec![(1, 1), (1, 3), (2, 2)], "{}", backend);
+        }
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let mut store = Backend::Parallel.open::<4>(4, 6, 2).unwrap();
+        assert!(matches!(
+            store.batch_send(vec![Record::fetch(1, 1)]),
+            Err(SpartaError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            store.batch_fetch(vec![Record::fetch(1, MAX_FETCH_VOLUME + 1)]),
+            Err(SpartaError::InvalidRequest(_))
+        ));
+
+        assert!(matches!(
+            Backend::Parallel.open::<4>(4, 6, 0),
+            Err(SpartaError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            Backend::Parallel.open::<4>(4, 6, 6),
+            Err(SpartaError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            Backend::Sequential.open::<4>(4, 6, MAX_SUBMAPS + 1),
+            Err(SpartaError::TooManySubmaps { .. })
+        ));
+    }
+}
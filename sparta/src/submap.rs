@@ -0,0 +1,630 @@
+use crate::codec::Decoder;
+use crate::error::{Result, SpartaError};
+use crate::omap::ObliviousMap;
+use crate::protocol;
+use crate::record::Record;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::{
+    cmp, io,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    sync::Mutex,
+    thread,
+};
+
+/// Size of the key a load balancer shares with its submaps.
+pub const LINK_KEY_SIZE: usize = 32;
+
+/// Fresh random key for the links between a load balancer and its submaps.
+pub fn generate_link_key() -> [u8; LINK_KEY_SIZE] {
+    let mut key = [0; LINK_KEY_SIZE];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Submap batches are far larger than client requests: this fits a fetch of
+/// a million messages of the default size, padding included.
+const MAX_SUBMAP_FRAME_SIZE: usize = 1 << 30;
+
+const TAG_SIZE: usize = 16;
+const RANDOM_SIZE: usize = 32;
+
+const HELLO: u8 = 0;
+const SEND: u8 = 1;
+const FETCH: u8 = 2;
+const COLLECT_GARBAGE: u8 = 3;
+const DRAIN: u8 = 4;
+const ENCODE: u8 = 5;
+const COMMIT: u8 = 6;
+const ROLLBACK: u8 = 7;
+
+const OK: u8 = 0;
+const ERROR: u8 = 1;
+
+/// Oblivious map the load balancer spreads messages over, either in its own
+/// memory or behind a connection to another process. Batches are tentative
+/// until `commit`, so that a batch some other submap failed can be undone.
+pub trait Submap<const N: usize>: Send {
+    /// Stores a batch of requests sent in `round`, each stamped with the round
+    /// it was first stored in through its `round` field.
+    fn batch_send(&mut self, requests: Vec<Record<N>>, round: u64) -> Result<()>;
+
+    /// Answers every fetch with one record: the matching message, or a dummy.
+    fn batch_fetch(&mut self, requests: Vec<Record<N>>) -> Result<Vec<Record<N>>>;
+
+    /// Evicts messages stored before `oldest_round`.
+    fn collect_garbage(&mut self, oldest_round: u64) -> Result<()>;
+
+    /// Removes and returns every stored record.
+    fn drain(&mut self) -> Result<Vec<Record<N>>>;
+
+    /// Appends the number of stored records and the records themselves, as
+    /// written to a snapshot.
+    fn encode(&self, bytes: &mut Vec<u8>) -> Result<()>;
+
+    /// Makes every batch since the last commit permanent.
+    fn commit(&mut self) -> Result<()>;
+
+    /// Undoes every batch since the last commit.
+    fn rollback(&mut self) -> Result<()>;
+}
+
+impl<const N: usize> Submap<N> for ObliviousMap<N> {
+    fn batch_send(&mut self, requests: Vec<Record<N>>, round: u64) -> Result<()> {
+        ObliviousMap::batch_send(self, requests, round);
+        Ok(())
+    }
+
+    fn batch_fetch(&mut self, requests: Vec<Record<N>>) -> Result<Vec<Record<N>>> {
+        Ok(ObliviousMap::batch_fetch(self, requests))
+    }
+
+    fn collect_garbage(&mut self, oldest_round: u64) -> Result<()> {
+        ObliviousMap::collect_garbage(self, oldest_round);
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<Vec<Record<N>>> {
+        Ok(ObliviousMap::drain(self))
+    }
+
+    fn encode(&self, bytes: &mut Vec<u8>) -> Result<()> {
+        bytes.extend_from_slice(&(self.len() as u64).to_le_bytes());
+        self.iter().for_each(|record| record.encode(bytes));
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        ObliviousMap::commit(self);
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        ObliviousMap::rollback(self);
+        Ok(())
+    }
+}
+
+fn encode_records<const N: usize>(records: &[Record<N>], bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&(records.len() as u64).to_le_bytes());
+    records.iter().for_each(|record| record.encode(bytes));
+}
+
+fn decode_records<const N: usize>(decoder: &mut Decoder) -> io::Result<Vec<Record<N>>> {
+    let len = decoder.u64()?;
+    (0..len).map(|_| Record::decode(decoder)).collect()
+}
+
+/// Connection between a load balancer and a submap. Each end sends a random
+/// value in the clear, and every later frame is sealed under a key derived
+/// from the shared link key and both values, with a nonce that counts the
+/// frames sent in its direction. Frames therefore cannot be read, forged,
+/// replayed or reordered, within a connection or across connections.
+struct Link {
+    stream: TcpStream,
+    cipher: ChaCha20Poly1305,
+    // leading nonce byte, which keeps the two directions apart
+    direction: u8,
+    sent: u64,
+    received: u64,
+}
+
+impl Link {
+    fn connect(stream: TcpStream, key: &[u8; LINK_KEY_SIZE]) -> io::Result<Self> {
+        let ours = Link::random();
+        protocol::write_frame(&mut &stream, &ours)?;
+        let theirs = Link::read_random(&stream)?;
+        Ok(Link::new(stream, key, &ours, &theirs, 0))
+    }
+
+    fn accept(stream: TcpStream, key: &[u8; LINK_KEY_SIZE]) -> io::Result<Self> {
+        let theirs = Link::read_random(&stream)?;
+        let ours = Link::random();
+        protocol::write_frame(&mut &stream, &ours)?;
+        Ok(Link::new(stream, key, &theirs, &ours, 1))
+    }
+
+    fn random() -> [u8; RANDOM_SIZE] {
+        let mut random = [0; RANDOM_SIZE];
+        OsRng.fill_bytes(&mut random);
+        random
+    }
+
+    fn read_random(stream: &TcpStream) -> io::Result<[u8; RANDOM_SIZE]> {
+        protocol::read_frame_with_limit(&mut &*stream, RANDOM_SIZE)?
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad link handshake"))
+    }
+
+    fn new(
+        stream: TcpStream,
+        key: &[u8; LINK_KEY_SIZE],
+        client: &[u8; RANDOM_SIZE],
+        server: &[u8; RANDOM_SIZE],
+        direction: u8,
+    ) -> Self {
+        let mut hasher = blake3::Hasher::new_keyed(key);
+        hasher.update(client);
+        hasher.update(server);
+        let session_key: [u8; LINK_KEY_SIZE] = hasher.finalize().into();
+        Link {
+            stream,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&session_key)),
+            direction,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    fn nonce(direction: u8, counter: u64) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[0] = direction;
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        nonce.into()
+    }
+
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        let nonce = Link::nonce(self.direction, self.sent);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, frame)
+            .map_err(|_| io::Error::other("submap link: encryption failed"))?;
+        self.sent += 1;
+        protocol::write_frame_with_limit(
+            &mut &self.stream,
+            &ciphertext,
+            MAX_SUBMAP_FRAME_SIZE + TAG_SIZE,
+        )
+    }
+
+    fn read(&mut self) -> io::Result<Vec<u8>> {
+        let ciphertext =
+            protocol::read_frame_with_limit(&mut &self.stream, MAX_SUBMAP_FRAME_SIZE + TAG_SIZE)?;
+        let nonce = Link::nonce(1 - self.direction, self.received);
+        let frame = self
+            .cipher
+            .decrypt(&nonce, &ciphertext[..])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "submap link: bad frame"))?;
+        self.received += 1;
+        Ok(frame)
+    }
+}
+
+/// Submap running in another process, reached over TCP. Each call sends one
+/// request frame and waits for its reply.
+pub struct RemoteSubmap<const N: usize> {
+    link: Mutex<Link>,
+}
+
+impl<const N: usize> RemoteSubmap<N> {
+    /// Connects to a submap started with `serve` for the same message size and
+    /// link key.
+    pub fn connect<A: ToSocketAddrs>(addr: A, key: &[u8; LINK_KEY_SIZE]) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let submap = RemoteSubmap {
+            link: Mutex::new(Link::connect(stream, key)?),
+        };
+        let mut request = vec![HELLO];
+        request.extend_from_slice(&(N as u64).to_le_bytes());
+        let reply = submap.call(&request)?;
+        let found = Decoder::new(&reply).u64()? as usize;
+        if found != N {
+            return Err(SpartaError::MessageSizeMismatch { expected: N, found });
+        }
+        Ok(submap)
+    }
+
+    fn call(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut link = self.link.lock().unwrap();
+        link.write(request)?;
+        let reply = link.read()?;
+        match reply.split_first() {
+            Some((&OK, body)) => Ok(body.to_vec()),
+            Some((&ERROR, msg)) => Err(SpartaError::Io(io::Error::other(
+                String::from_utf8_lossy(msg).into_owned(),
+            ))),
+            _ => Err(SpartaError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad submap reply",
+            ))),
+        }
+    }
+
+    fn call_for_records(&self, request: &[u8]) -> Result<Vec<Record<N>>> {
+        let reply = self.call(request)?;
+        Ok(decode_records(&mut Decoder::new(&reply))?)
+    }
+}
+
+impl<const N: usize> Submap<N> for RemoteSubmap<N> {
+    fn batch_send(&mut self, requests: Vec<Record<N>>, round: u64) -> Result<()> {
+        let mut request = vec![SEND];
+        request.extend_from_slice(&round.to_le_bytes());
+        encode_records(&requests, &mut request);
+        self.call(&request)?;
+        Ok(())
+    }
+
+    fn batch_fetch(&mut self, requests: Vec<Record<N>>) -> Result<Vec<Record<N>>> {
+        let mut request = vec![FETCH];
+        encode_records(&requests, &mut request);
+        self.call_for_records(&request)
+    }
+
+    fn collect_garbage(&mut self, oldest_round: u64) -> Result<()> {
+        let mut request = vec![COLLECT_GARBAGE];
+        request.extend_from_slice(&oldest_round.to_le_bytes());
+        self.call(&request)?;
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<Vec<Record<N>>> {
+        self.call_for_records(&[DRAIN])
+    }
+
+    fn encode(&self, bytes: &mut Vec<u8>) -> Result<()> {
+        bytes.extend_from_slice(&self.call(&[ENCODE])?);
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.call(&[COMMIT])?;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        self.call(&[ROLLBACK])?;
+        Ok(())
+    }
+}
+
+/// Submap kept on several replicas that are sent identical batches, so that
+/// it survives losing all but one of them. A replica that fails is dropped;
+/// a batch only fails when every replica fails it. When more than half of
+/// the replicas agree on an answer, those that gave another are dropped as
+/// well. Without such a majority, as with two replicas that disagree, a tie
+/// or answers that all differ, the first answer is taken and all are kept.
+///
+/// Dropped replicas never rejoin: there is no state transfer to bring one
+/// back up to date. Replacing them takes restoring the load balancer from a
+/// snapshot into fresh replicas.
+pub struct ReplicatedSubmap<const N: usize> {
+    replicas: Vec<Box<dyn Submap<N>>>,
+}
+
+impl<const N: usize> ReplicatedSubmap<N> {
+    pub fn new(replicas: Vec<Box<dyn Submap<N>>>) -> Result<Self> {
+        if replicas.is_empty() {
+            return Err(SpartaError::InvalidConfig(
+                "at least one replica is required",
+            ));
+        }
+        Ok(ReplicatedSubmap { replicas })
+    }
+
+    /// Number of replicas still in use.
+    pub fn num_replicas(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// Runs `f` on every replica at once and drops the replicas it failed on,
+    /// unless it failed on all of them. Returns the results of the remaining
+    /// replicas in order.
+    fn run<T: Send>(
+        &mut self,
+        f: impl Fn(&mut dyn Submap<N>) -> Result<T> + Sync,
+    ) -> Result<Vec<T>> {
+        let f = &f;
+        let results: Vec<Result<T>> = thread::scope(|s| {
+            let handles: Vec<_> = self
+                .replicas
+                .iter_mut()
+                .map(|replica| s.spawn(move || f(replica.as_mut())))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(io::Error::other("replica panicked").into()))
+                })
+                .collect()
+        });
+
+        if results.iter().all(|result| result.is_err()) {
+            return Err(results.into_iter().find_map(|result| result.err()).unwrap());
+        }
+        let mut ok = results.iter().map(|result| result.is_ok());
+        self.replicas.retain(|_| ok.next().unwrap());
+        Ok(results
+            .into_iter()
+            .filter_map(|result| result.ok())
+            .collect())
+    }
+
+    /// Keeps the answer more than half of the replicas agree on and drops the
+    /// replicas that gave another. Without such a majority, as with two
+    /// replicas that disagree, the first answer is kept and no replica dropped.
+    fn reconcile(&mut self, mut answers: Vec<Vec<Record<N>>>) -> Vec<Record<N>> {
+        let votes = |answer: &Vec<Record<N>>| answers.iter().filter(|a| *a == answer).count();
+        let best = (0..answers.len())
+            .max_by_key(|&i| (votes(&answers[i]), cmp::Reverse(i)))
+            .unwrap();
+        if votes(&answers[best]) * 2 <= answers.len() {
+            return answers.swap_remove(0);
+        }
+
+        let mut agrees: Vec<bool> = answers.iter().map(|a| *a == answers[best]).collect();
+        agrees.reverse();
+        self.replicas.retain(|_| agrees.pop().unwrap());
+        answers.swap_remove(best)
+    }
+}
+
+impl<const N: usize> Submap<N> for ReplicatedSubmap<N> {
+    fn batch_send(&mut self, requests: Vec<Record<N>>, round: u64) -> Result<()> {
+        self.run(|replica| replica.batch_send(requests.clone(), round))?;
+        Ok(())
+    }
+
+    fn batch_fetch(&mut self, requests: Vec<Record<N>>) -> Result<Vec<Record<N>>> {
+        let answers = self.run(|replica| replica.batch_fetch(requests.clone()))?;
+        Ok(self.reconcile(answers))
+    }
+
+    fn collect_garbage(&mut self, oldest_round: u64) -> Result<()> {
+        self.run(|replica| replica.collect_garbage(oldest_round))?;
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<Vec<Record<N>>> {
+        let answers = self.run(|replica| replica.drain())?;
+        Ok(self.reconcile(answers))
+    }
+
+    /// Encodes the first replica that answers.
+    fn encode(&self, bytes: &mut Vec<u8>) -> Result<()> {
+        let mut result = Ok(());
+        for replica in self.replicas.iter() {
+            result = replica.encode(bytes);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.run(|replica| replica.commit())?;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        self.run(|replica| replica.rollback())?;
+        Ok(())
+    }
+}
+
+fn handle<const N: usize>(submap: &mut ObliviousMap<N>, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoder = Decoder::new(request);
+    let mut reply = vec![OK];
+    match decoder.u8()? {
+        HELLO => reply.extend_from_slice(&(N as u64).to_le_bytes()),
+        SEND => {
+            let round = decoder.u64()?;
+            submap.batch_send(decode_records(&mut decoder)?, round);
+        }
+        FETCH => {
+            let responses = submap.batch_fetch(decode_records(&mut decoder)?);
+            encode_records(&responses, &mut reply);
+        }
+        COLLECT_GARBAGE => submap.collect_garbage(decoder.u64()?),
+        DRAIN => encode_records(&submap.drain(), &mut reply),
+        ENCODE => Submap::encode(submap, &mut reply).map_err(io::Error::other)?,
+        COMMIT => submap.commit(),
+        ROLLBACK => submap.rollback(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown submap request",
+            ))
+        }
+    }
+    Ok(reply)
+}
+
+/// Serves a submap on `listener` until the process exits. Load balancers are
+/// served one connection at a time, and the stored messages outlive each
+/// connection. Only peers holding the link `key` get past the first frame. A
+/// malformed request is answered with an error and closes the connection.
+/// Batches left uncommitted when a connection closes, such as the one in
+/// flight when a load balancer crashes, are rolled back.
+pub fn serve<const N: usize>(
+    listener: TcpListener,
+    num_threads: usize,
+    key: &[u8; LINK_KEY_SIZE],
+) -> Result<()> {
+    let mut submap = ObliviousMap::<N>::new(num_threads)?;
+    for stream in listener.incoming() {
+        let stream = stream?;
+        stream.set_nodelay(true)?;
+        let Ok(mut link) = Link::accept(stream, key) else {
+            continue;
+        };
+
+        while let Ok(request) = link.read() {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| handle(&mut submap, &request)))
+                .unwrap_or_else(|_| Err(io::Error::other("submap panicked")));
+            match result {
+                Ok(reply) => {
+                    if link.write(&reply).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let mut reply = vec![ERROR];
+                    reply.extend_from_slice(e.to_string().as_bytes());
+                    let _ = link.write(&reply);
+                    break;
+                }
+            }
+        }
+        submap.rollback();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{RecordType, Uid};
+
+    // replica whose machine is gone
+    struct LostReplica;
+
+    fn lost<T>() -> Result<T> {
+        Err(io::Error::from(io::ErrorKind::ConnectionReset).into())
+    }
+
+    impl Submap<4> for LostReplica {
+        fn batch_send(&mut self, _: Vec<Record<4>>, _: u64) -> Result<()> {
+            lost()
+        }
+
+        fn batch_fetch(&mut self, _: Vec<Record<4>>) -> Result<Vec<Record<4>>> {
+            lost()
+        }
+
+        fn collect_garbage(&mut self, _: u64) -> Result<()> {
+            lost()
+        }
+
+        fn drain(&mut self) -> Result<Vec<Record<4>>> {
+            lost()
+        }
+
+        fn encode(&self, _: &mut Vec<u8>) -> Result<()> {
+            lost()
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            lost()
+        }
+
+        fn rollback(&mut self) -> Result<()> {
+            lost()
+        }
+    }
+
+    #[test]
+    fn test_remote_submap() {
+        let key = [5; LINK_KEY_SIZE];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve::<4>(listener, 1, &key));
+
+        assert!(matches!(
+            RemoteSubmap::<8>::connect(addr, &key),
+            Err(SpartaError::MessageSizeMismatch {
+                expected: 8,
+                found: 4
+            })
+        ));
+        assert!(RemoteSubmap::<4>::connect(addr, &[6; LINK_KEY_SIZE]).is_err());
+
+        let mut submap = RemoteSubmap::<4>::connect(addr, &key).unwrap();
+        let mut send = Record::send(1, [7; 4]);
+        send.idx = 3;
+        submap.batch_send(vec![send], 0).unwrap();
+
+        let fetch = Record::new(1, RecordType::Fetch, 0, 0, 3);
+        let responses = submap.batch_fetch(vec![fetch]).unwrap();
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].is_send() && responses[0].message == [7; 4]);
+
+        let stored = submap.drain().unwrap();
+        let mut bytes = Vec::new();
+        submap.encode(&mut bytes).unwrap();
+        assert_eq!(bytes, 0u64.to_le_bytes());
+        assert!(stored.iter().all(|record| !record.is_send()));
+    }
+
+    #[test]
+    fn test_replicated_submap() {
+        let replicas: Vec<Box<dyn Submap<4>>> = vec![
+            Box::new(LostReplica),
+            Box::new(ObliviousMap::<4>::new(1).unwrap()),
+            Box::new(ObliviousMap::<4>::new(1).unwrap()),
+        ];
+        let mut submap = ReplicatedSubmap::new(replicas).unwrap();
+
+        let mut send = Record::send(1, [7; 4]);
+        send.idx = 3;
+        submap.batch_send(vec![send], 0).unwrap();
+        submap.commit().unwrap();
+        assert_eq!(submap.num_replicas(), 2);
+
+        let fetch = Record::new(1, RecordType::Fetch, 0, 0, 3);
+        let responses = submap.batch_fetch(vec![fetch]).unwrap();
+        assert!(responses[0].is_send() && responses[0].message == [7; 4]);
+
+        // two replicas cannot outvote each other, even when they disagree
+        let mut stale = ObliviousMap::<4>::new(1).unwrap();
+        stale.batch_send(vec![Record::send(2, [1; 4])], 0);
+        let replicas: Vec<Box<dyn Submap<4>>> = vec![
+            Box::new(ObliviousMap::<4>::new(1).unwrap()),
+            Box::new(stale),
+        ];
+        let mut pair = ReplicatedSubmap::new(replicas).unwrap();
+        assert!(pair.drain().unwrap().is_empty());
+        assert_eq!(pair.num_replicas(), 2);
+
+        // nor can replicas without a majority, with a tie or all disagreeing
+        let holding = |uid: Uid| -> Box<dyn Submap<4>> {
+            let mut omap = ObliviousMap::<4>::new(1).unwrap();
+            omap.batch_send(vec![Record::send(uid, [1; 4])], 0);
+            Box::new(omap)
+        };
+        let empty = || -> Box<dyn Submap<4>> { Box::new(ObliviousMap::<4>::new(1).unwrap()) };
+        let mut split = ReplicatedSubmap::new(vec![empty(), holding(2), holding(3)]).unwrap();
+        assert!(split.drain().unwrap().is_empty());
+        assert_eq!(split.num_replicas(), 3);
+        let mut tie =
+            ReplicatedSubmap::new(vec![holding(2), holding(2), empty(), empty()]).unwrap();
+        assert_eq!(tie.drain().unwrap().len(), 1);
+        assert_eq!(tie.num_replicas(), 4);
+        let mut outvoted =
+            ReplicatedSubmap::new(vec![holding(2), empty(), empty(), empty()]).unwrap();
+        assert!(outvoted.drain().unwrap().is_empty());
+        assert_eq!(outvoted.num_replicas(), 3);
+
+        let mut lost = ReplicatedSubmap::<4>::new(vec![Box::new(LostReplica)]).unwrap();
+        assert!(lost.commit().is_err());
+        assert_eq!(lost.num_replicas(), 1);
+    }
+}